@@ -0,0 +1,34 @@
+extern crate pioneer;
+
+use pioneer::downhill_map::DownhillMap;
+use pioneer::flow_map::FlowMap;
+use pioneer::mesh::Mesh;
+use pioneer::mesh_splitter::MeshSplitter;
+use pioneer::single_downhill_map::{RandomDownhillMap, SingleDownhillMap};
+use std::time::{Duration, Instant};
+
+fn time<F: Fn() -> FlowMap>(function: F, iterations: u32) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        function();
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    let mut rng = Box::new(pioneer::thread_rng());
+    let mut mesh = Mesh::new(1, 0.0);
+    mesh.set_z(0, 0, 1.0);
+    let mesh = MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 9);
+
+    let downhill_map = DownhillMap::new(&mesh);
+    let random_downhill_map: Box<SingleDownhillMap> =
+        Box::new(RandomDownhillMap::new(&downhill_map, &mut rng));
+
+    let from = time(|| FlowMap::from(&mesh, &random_downhill_map), 3);
+    let from_topological = time(|| FlowMap::from_topological(&mesh, &random_downhill_map), 3);
+
+    println!("Mesh width {}", mesh.get_width());
+    println!("FlowMap::from             {:?}", from);
+    println!("FlowMap::from_topological {:?}", from_topological);
+}
@@ -0,0 +1,393 @@
+extern crate pioneer;
+
+use pioneer::heightmap::Heightmap;
+use pioneer::mesh::Mesh;
+use pioneer::pipeline::{Pipeline, Stage};
+use pioneer::render::TerrainImage;
+use pioneer::scale::Scale;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "Usage: pioneer-gen [--config FILE] [--KEY VALUE]...
+
+Generates a world and writes heightmap.png, flow.png, rivers.png and rivers.csv to the output
+directory, leaving out flow.png if a pipeline file computes no flow. Settings are read from the
+config file, if given, and then from flags. Config files have one `key = value` per line, with
+`#` starting a comment. Keys are:
+
+    pipeline            TOML or JSON pipeline file to run, by extension. Without one, the
+                        stages are built from levels, random_range, erosion_threshold,
+                        erosion_samples, river_threshold and flow_to_width. Needs the serde
+                        feature
+    seed                64-bit seed for all randomness (default 0)
+    levels              Number of times to split a single cell (default 8)
+    random_range        Range of random values for splits, as MIN,MAX (default 0,1)
+    erosion_threshold   Flow above which cells are eroded (default 64)
+    erosion_samples     Number of erosion samples (default 4)
+    sea_level           Height below which there are no rivers (default 0)
+    river_threshold     Flow above which cells have rivers (default 32)
+    flow_to_width       River widths at the threshold and maximum flow, as MIN,MAX
+                        (default 0.05,0.5)
+    output              Output directory (default output)
+
+Flags use the same keys, with `-` or `_` between words, e.g. --sea-level 0.25.";
+
+#[derive(Debug, PartialEq)]
+struct Config {
+    seed: u64,
+    levels: u32,
+    random_range: (f64, f64),
+    erosion_threshold: f64,
+    erosion_samples: usize,
+    sea_level: f64,
+    river_threshold: f64,
+    flow_to_width: (f64, f64),
+    output: PathBuf,
+    pipeline: Option<PathBuf>,
+}
+
+impl Config {
+    fn new() -> Config {
+        Config {
+            seed: 0,
+            levels: 8,
+            random_range: (0.0, 1.0),
+            erosion_threshold: 64.0,
+            erosion_samples: 4,
+            sea_level: 0.0,
+            river_threshold: 32.0,
+            flow_to_width: (0.05, 0.5),
+            output: PathBuf::from("output"),
+            pipeline: None,
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+        value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {}", key, value))
+    }
+
+    fn parse_range(key: &str, value: &str) -> Result<(f64, f64), String> {
+        let parts: Vec<&str> = value.split(',').collect();
+        if parts.len() != 2 {
+            return Err(format!("{} must be MIN,MAX but was {}", key, value));
+        }
+        Ok((Config::parse(key, parts[0])?, Config::parse(key, parts[1])?))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let key = key.trim().replace('-', "_");
+        let key = key.as_str();
+        match key {
+            "seed" => self.seed = Config::parse(key, value)?,
+            "levels" => self.levels = Config::parse(key, value)?,
+            "random_range" => self.random_range = Config::parse_range(key, value)?,
+            "erosion_threshold" => self.erosion_threshold = Config::parse(key, value)?,
+            "erosion_samples" => self.erosion_samples = Config::parse(key, value)?,
+            "sea_level" => self.sea_level = Config::parse(key, value)?,
+            "river_threshold" => self.river_threshold = Config::parse(key, value)?,
+            "flow_to_width" => self.flow_to_width = Config::parse_range(key, value)?,
+            "output" => self.output = PathBuf::from(value.trim()),
+            "pipeline" => self.pipeline = Some(PathBuf::from(value.trim())),
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    fn read_file(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => self.set(key, value)?,
+                _ => return Err(format!("Line {} is not key = value: {}", number + 1, line)),
+            }
+        }
+        Ok(())
+    }
+
+    // The config file is read first wherever it appears, so flags always take precedence
+    fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut out = Config::new();
+        let mut flags = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            if arg == "--config" {
+                let text = fs::read_to_string(value)
+                    .map_err(|error| format!("Could not read {}: {}", value, error))?;
+                out.read_file(&text)?;
+            } else {
+                flags.push((&arg[2..], value));
+            }
+        }
+        for (key, value) in flags {
+            out.set(key, value)?;
+        }
+        Ok(out)
+    }
+
+    fn get_pipeline(&self) -> Result<Pipeline, String> {
+        match &self.pipeline {
+            Some(path) => Config::read_pipeline(path),
+            None => Ok(self.get_default_pipeline()),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn read_pipeline(path: &Path) -> Result<Pipeline, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| format!("Could not read {}: {}", path.display(), error))?;
+        let pipeline = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Pipeline::from_json(&text),
+            _ => Pipeline::from_toml(&text),
+        };
+        pipeline.map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn read_pipeline(path: &Path) -> Result<Pipeline, String> {
+        Err(format!(
+            "Cannot read {}: pipeline files need the serde feature",
+            path.display()
+        ))
+    }
+
+    fn get_default_pipeline(&self) -> Pipeline {
+        Pipeline::new(vec![
+            Stage::Split {
+                levels: self.levels,
+                random_range: self.random_range,
+            },
+            Stage::Erode {
+                threshold: self.erosion_threshold,
+                samples: self.erosion_samples,
+                strength: None,
+                max_erosion: None,
+                repeat: false,
+                sea_level: None,
+            },
+            // Erosion can leave pits, which rivers could not flow out of
+            Stage::FillDepressions { epsilon: 1e-6 },
+            Stage::Rivers {
+                threshold: self.river_threshold,
+                sea_level: self.sea_level,
+                flow_to_width: self.flow_to_width,
+            },
+        ])
+    }
+}
+
+fn write_error<E: fmt::Display>(path: &Path, error: E) -> String {
+    format!("Could not write {}: {}", path.display(), error)
+}
+
+fn generate(config: &Config) -> Result<(), String> {
+    let world = config
+        .get_pipeline()?
+        .run(config.seed)
+        .map_err(|error| error.to_string())?;
+    let mesh = world.get_mesh();
+    // Pipeline files need not compute flow or rivers
+    let junctions = world
+        .get_junctions()
+        .map_or(&[][..], |junctions| &junctions[..]);
+    let rivers = world.get_rivers().map_or(&[][..], |rivers| &rivers[..]);
+
+    let output = &config.output;
+    fs::create_dir_all(output)
+        .map_err(|error| format!("Could not create {}: {}", output.display(), error))?;
+
+    let path = output.join("heightmap.png");
+    let z = mesh.get_z_vector();
+    let to_png = Scale::new((z.min(), z.max()), (0.0, 65535.0));
+    Heightmap::save(mesh, &to_png, &path).map_err(|error| write_error(&path, error))?;
+
+    // Square root so that small streams are visible next to large rivers
+    if let Some(flow_map) = world.get_flow_map() {
+        let path = output.join("flow.png");
+        let mut flow_mesh = Mesh::with_size(mesh.get_width(), mesh.get_height(), 0.0);
+        flow_mesh.set_z_vector(flow_map.get_flow_matrix().map(f64::sqrt));
+        let to_png = Scale::new((0.0, flow_map.get_max_flow().sqrt()), (0.0, 65535.0));
+        Heightmap::save(&flow_mesh, &to_png, &path).map_err(|error| write_error(&path, error))?;
+    }
+
+    let path = output.join("rivers.png");
+    let mut image = TerrainImage::new(mesh, config.sea_level, 4);
+    image.hillshade(mesh, config.sea_level, 5.5, 0.8, 10.0);
+    image.draw_rivers(junctions, rivers, [40, 90, 200]);
+    image
+        .save(&path)
+        .map_err(|error| write_error(&path, error))?;
+
+    let path = output.join("rivers.csv");
+    let write_csv = || -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writeln!(writer, "from_x,from_y,to_x,to_y")?;
+        for river in rivers.iter() {
+            let (from, to) = (river.from(), river.to());
+            writeln!(writer, "{},{},{},{}", from.x, from.y, to.x, to.y)?;
+        }
+        writer.flush()
+    };
+    write_csv().map_err(|error| write_error(&path, error))?;
+
+    println!(
+        "Generated {}x{} world with {} river segments in {}",
+        mesh.get_width(),
+        mesh.get_height(),
+        rivers.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let result = Config::from_args(&args).and_then(|config| generate(&config));
+    if let Err(message) = result {
+        eprintln!("{}\n\n{}", message, USAGE);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let actual = Config::from_args(&args(&[
+            "--seed",
+            "7",
+            "--random-range",
+            "0.1, 0.9",
+            "--sea_level",
+            "0.25",
+        ]))
+        .unwrap();
+
+        let mut expected = Config::new();
+        expected.seed = 7;
+        expected.random_range = (0.1, 0.9);
+        expected.sea_level = 0.25;
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_read_file() {
+        let mut actual = Config::new();
+        actual
+            .read_file("# World\nlevels = 5\n\nflow_to_width = 0.1,1 # widths\noutput = a b\n")
+            .unwrap();
+
+        let mut expected = Config::new();
+        expected.levels = 5;
+        expected.flow_to_width = (0.1, 1.0);
+        expected.output = PathBuf::from("a b");
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn flags_should_override_config_file() {
+        let path = env::temp_dir().join(format!("pioneer-gen-{}.conf", process::id()));
+        fs::write(&path, "seed = 1\nlevels = 3\n").unwrap();
+
+        let actual = Config::from_args(&args(&["--seed", "2", "--config", path.to_str().unwrap()]));
+        fs::remove_file(&path).unwrap();
+
+        let actual = actual.unwrap();
+        assert_eq!(actual.seed, 2);
+        assert_eq!(actual.levels, 3);
+    }
+
+    #[test]
+    fn should_reject_bad_arguments() {
+        assert!(Config::from_args(&args(&["--seed"])).is_err());
+        assert!(Config::from_args(&args(&["--seed", "x"])).is_err());
+        assert!(Config::from_args(&args(&["--colour", "blue"])).is_err());
+        assert!(Config::from_args(&args(&["seed", "1"])).is_err());
+        assert!(Config::from_args(&args(&["--random-range", "1"])).is_err());
+        assert!(Config::new().read_file("levels 5").is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut config = Config::new();
+        config.levels = 4;
+        config.river_threshold = 4.0;
+        config.erosion_threshold = 8.0;
+        config.output = env::temp_dir().join(format!("pioneer-gen-{}", process::id()));
+
+        generate(&config).unwrap();
+
+        for name in ["heightmap.png", "flow.png", "rivers.png", "rivers.csv"].iter() {
+            assert!(config.output.join(name).exists());
+        }
+        let heightmap = Heightmap::load(
+            config.output.join("heightmap.png"),
+            &Scale::new((0.0, 65535.0), (0.0, 1.0)),
+            0.0,
+        )
+        .unwrap();
+        assert_eq!(heightmap.get_shape(), (16, 16));
+        fs::remove_dir_all(&config.output).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_generate_from_pipeline_file() {
+        let output = env::temp_dir().join(format!("pioneer-gen-pipeline-{}", process::id()));
+        fs::create_dir_all(&output).unwrap();
+        let path = output.join("pipeline.toml");
+        fs::write(
+            &path,
+            "[[stages]]\nstage = \"split\"\nlevels = 3\nrandom_range = [0.0, 1.0]\n",
+        )
+        .unwrap();
+        let config = Config::from_args(&args(&[
+            "--pipeline",
+            path.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+        ]))
+        .unwrap();
+
+        generate(&config).unwrap();
+
+        let heightmap = Heightmap::load(
+            output.join("heightmap.png"),
+            &Scale::new((0.0, 65535.0), (0.0, 1.0)),
+            0.0,
+        )
+        .unwrap();
+        assert_eq!(heightmap.get_shape(), (8, 8));
+        assert!(!output.join("flow.png").exists());
+        assert!(output.join("rivers.csv").exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+}
@@ -0,0 +1,247 @@
+use downhill_map::DIRECTIONS;
+use mesh::Mesh;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Debug, PartialEq)]
+struct Cell {
+    x: i32,
+    y: i32,
+    z: f64,
+}
+
+impl Eq for Cell {}
+
+impl Ord for Cell {
+    // Reversed so that BinaryHeap pops the lowest cell first
+    fn cmp(&self, other: &Cell) -> Ordering {
+        other
+            .z
+            .partial_cmp(&self.z)
+            .unwrap()
+            .then_with(|| other.x.cmp(&self.x))
+            .then_with(|| other.y.cmp(&self.y))
+    }
+}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Cell) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct DepressionFiller {}
+
+impl DepressionFiller {
+    /// Priority-flood from the edges water can leave the mesh by (see `Boundary::Constant`).
+    /// Every cell ends up at least `epsilon` above the cell it was flooded from, so each cell has
+    /// a strictly downhill path off the mesh.
+    pub fn fill(mesh: &Mesh, epsilon: f64) -> Mesh {
+        assert!(epsilon > 0.0, "Epsilon must be positive");
+        let (out, _, _) = DepressionFiller::flood(mesh, epsilon);
+        out
+    }
+
+    /// Returns the flooded mesh, for each cell the index into `DIRECTIONS` of the cell it was
+    /// flooded from (edge cells point to the lowest exit), and the order cells were flooded in.
+    pub fn flood(mesh: &Mesh, epsilon: f64) -> (Mesh, na::DMatrix<u8>, Vec<(i32, i32)>) {
+        let (width, height) = (mesh.get_width(), mesh.get_height());
+        let mut out = mesh.clone();
+        let mut directions = na::DMatrix::zeros(width as usize, height as usize);
+        let mut visited = vec![vec![false; height as usize]; width as usize];
+        let mut queue = BinaryHeap::with_capacity(((width + height) * 2) as usize);
+        let mut order = Vec::with_capacity(mesh.get_cell_count());
+
+        for x in 0..width {
+            for y in 0..height {
+                let exit = DIRECTIONS
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (dx, dy))| mesh.resolve(x + dx, y + dy).is_none())
+                    .map(|(d, (dx, dy))| (d, mesh.get_z(x + dx, y + dy)))
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                if let Some((direction, exit_z)) = exit {
+                    let z = DepressionFiller::raise(mesh.get_z(x, y), exit_z, epsilon);
+                    out.set_z(x, y, z);
+                    directions[(x as usize, y as usize)] = direction as u8;
+                    visited[x as usize][y as usize] = true;
+                    queue.push(Cell { x, y, z });
+                }
+            }
+        }
+
+        assert!(
+            !queue.is_empty(),
+            "Mesh must have an edge that water can leave by"
+        );
+
+        while let Some(cell) = queue.pop() {
+            order.push((cell.x, cell.y));
+            for direction in DIRECTIONS.iter() {
+                if let Some((nx, ny)) = mesh.get_neighbour(cell.x, cell.y, *direction) {
+                    if visited[nx as usize][ny as usize] {
+                        continue;
+                    }
+                    // Usually the reverse of `direction`, but not across a mirrored edge
+                    let back = DIRECTIONS
+                        .iter()
+                        .position(|d| mesh.get_neighbour(nx, ny, *d) == Some((cell.x, cell.y)));
+                    if let Some(back) = back {
+                        let z = DepressionFiller::raise(mesh.get_z(nx, ny), cell.z, epsilon);
+                        out.set_z(nx, ny, z);
+                        directions[(nx as usize, ny as usize)] = back as u8;
+                        visited[nx as usize][ny as usize] = true;
+                        queue.push(Cell { x: nx, y: ny, z });
+                    }
+                }
+            }
+        }
+
+        (out, directions, order)
+    }
+
+    fn raise(z: f64, downhill_z: f64, epsilon: f64) -> f64 {
+        let min_z = downhill_z + epsilon;
+        if z < min_z {
+            min_z
+        } else {
+            z
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use downhill_map::DownhillMap;
+
+    #[test]
+    fn test_fill_pit() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.5, 0.8, 0.2, 0.3, 0.1, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        let mut expected = mesh.clone();
+        expected.set_z(1, 1, 0.3 + 0.01);
+
+        let actual = DepressionFiller::fill(&mesh, 0.01);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_fill_flat() {
+        let mut mesh = Mesh::new(5, 0.0);
+        let mut z = na::DMatrix::repeat(5, 5, 0.5);
+        z[(0, 2)] = 0.2;
+        mesh.set_z_vector(z);
+
+        let actual = DepressionFiller::fill(&mesh, 0.01);
+
+        assert_eq!(actual.get_z(0, 2), 0.2);
+        assert_eq!(actual.get_z(1, 2), 0.5);
+        assert!(actual.get_z(2, 2) > 0.5);
+        assert_eq!(DownhillMap::new(&actual).all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn test_fill_edge_below_out_of_bounds() {
+        let mut mesh = Mesh::new(1, 1.0);
+        mesh.set_z(0, 0, 0.5);
+
+        let actual = DepressionFiller::fill(&mesh, 0.25);
+
+        assert_eq!(actual.get_z(0, 0), 1.25);
+    }
+
+    #[test]
+    fn test_flood_directions() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.5, 0.8, 0.2, 0.3, 0.1, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        let (_, directions, order) = DepressionFiller::flood(&mesh, 0.01);
+
+        assert_eq!(order[0], (0, 2));
+        assert_eq!(order[8], (1, 2));
+        assert_eq!(directions[(1, 1)], 1);
+        assert_eq!(directions[(0, 0)], 0);
+        assert_eq!(directions[(2, 2)], 2);
+    }
+
+    #[test]
+    fn fill_should_give_all_cells_downhill() {
+        let mut mesh = Mesh::new(4, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.9, 0.9, 0.9, 0.9, 0.9, 0.1, 0.2, 0.9, 0.9, 0.2, 0.1, 0.9, 0.9, 0.9, 0.3, 0.9,
+            ],
+        ));
+        assert_eq!(DownhillMap::new(&mesh).all_cells_have_downhill(), false);
+
+        let actual = DepressionFiller::fill(&mesh, 0.001);
+
+        assert_eq!(DownhillMap::new(&actual).all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn fill_should_drain_to_single_coastline() {
+        use edge_policy::{Boundary, EdgePolicy};
+
+        let mut mesh = Mesh::with_edge_policy(
+            4,
+            4,
+            EdgePolicy::new(
+                Boundary::Constant(0.0),
+                Boundary::Clamp,
+                Boundary::Clamp,
+                Boundary::Clamp,
+            ),
+        );
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.9, 0.9, 0.9, 0.9, 0.9, 0.1, 0.2, 0.9, 0.9, 0.2, 0.1, 0.9, 0.9, 0.9, 0.3, 0.9,
+            ],
+        ));
+
+        let actual = DepressionFiller::fill(&mesh, 0.001);
+
+        let downhill_map = DownhillMap::new(&actual);
+        assert_eq!(downhill_map.all_cells_have_downhill(), true);
+        assert!(actual.get_z(3, 3) > actual.get_z(0, 3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Mesh must have an edge that water can leave by")]
+    fn fill_should_reject_mesh_without_exit() {
+        use edge_policy::{Boundary, EdgePolicy};
+
+        let mesh = Mesh::with_edge_policy(2, 2, EdgePolicy::all(Boundary::Mirror));
+
+        DepressionFiller::fill(&mesh, 0.001);
+    }
+
+    #[test]
+    fn fill_should_not_change_mesh_with_downhill() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        assert_eq!(DepressionFiller::fill(&mesh, 0.01), mesh);
+    }
+
+}
@@ -2,20 +2,67 @@ use mesh::Mesh;
 
 pub const DIRECTIONS: [(i32, i32); 4] = [(-1, 0), (0, -1), (1, 0), (0, 1)];
 
+// The first four are the same as DIRECTIONS so that direction indices mean the same thing in
+// either neighbourhood
+pub const ALL_DIRECTIONS: [(i32, i32); 8] = [
+    (-1, 0),
+    (0, -1),
+    (1, 0),
+    (0, 1),
+    (-1, -1),
+    (1, -1),
+    (1, 1),
+    (-1, 1),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Neighbourhood {
+    Four,
+    Eight,
+}
+
+impl Neighbourhood {
+    pub fn get_directions(&self) -> &'static [(i32, i32)] {
+        match self {
+            Neighbourhood::Four => &DIRECTIONS,
+            Neighbourhood::Eight => &ALL_DIRECTIONS,
+        }
+    }
+}
+
+pub fn get_distance(direction: (i32, i32)) -> f64 {
+    ((direction.0 * direction.0 + direction.1 * direction.1) as f64).sqrt()
+}
+
+pub fn get_slope(mesh: &Mesh, x: i32, y: i32, direction: (i32, i32)) -> f64 {
+    let drop = mesh.get_z(x, y) - mesh.get_z(x + direction.0, y + direction.1);
+    drop / get_distance(direction)
+}
+
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DownhillMap {
     width: i32,
-    directions: na::DMatrix<[bool; 4]>,
+    height: i32,
+    neighbourhood: Neighbourhood,
+    directions: na::DMatrix<[bool; 8]>,
 }
 
 impl DownhillMap {
     pub fn new(mesh: &Mesh) -> DownhillMap {
+        DownhillMap::with_neighbourhood(mesh, Neighbourhood::Four)
+    }
+
+    pub fn with_neighbourhood(mesh: &Mesh, neighbourhood: Neighbourhood) -> DownhillMap {
         let mut out = DownhillMap {
             width: mesh.get_width(),
+            height: mesh.get_height(),
+            neighbourhood,
             directions: na::DMatrix::repeat(
                 mesh.get_width() as usize,
-                mesh.get_width() as usize,
-                [false; 4],
+                mesh.get_height() as usize,
+                [false; 8],
             ),
         };
         out.compute_all_directions(mesh);
@@ -26,20 +73,26 @@ impl DownhillMap {
         self.width
     }
 
-    pub fn get_directions(&self, x: i32, y: i32) -> [bool; 4] {
-        self.directions[(x as usize, y as usize)]
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn get_neighbourhood(&self) -> Neighbourhood {
+        self.neighbourhood
+    }
+
+    pub fn get_directions(&self, x: i32, y: i32) -> &[bool] {
+        &self.directions[(x as usize, y as usize)][..self.neighbourhood.get_directions().len()]
     }
 
-    fn set_directions(&mut self, x: i32, y: i32, directions: [bool; 4]) {
+    fn set_directions(&mut self, x: i32, y: i32, directions: [bool; 8]) {
         self.directions[(x as usize, y as usize)] = directions;
     }
 
-    fn compute_directions(mesh: &Mesh, x: i32, y: i32) -> [bool; 4] {
+    fn compute_directions(mesh: &Mesh, neighbourhood: Neighbourhood, x: i32, y: i32) -> [bool; 8] {
         let z = mesh.get_z(x, y);
-        let mut out = [false; 4];
-        for d in 0..DIRECTIONS.len() {
-            let dx = DIRECTIONS[d].0;
-            let dy = DIRECTIONS[d].1;
+        let mut out = [false; 8];
+        for (d, (dx, dy)) in neighbourhood.get_directions().iter().enumerate() {
             out[d] = mesh.get_z(x + dx, y + dy) < z;
         }
         out
@@ -47,14 +100,23 @@ impl DownhillMap {
 
     fn compute_all_directions(&mut self, mesh: &Mesh) {
         for x in 0..mesh.get_width() {
-            for y in 0..mesh.get_width() {
-                let directions = DownhillMap::compute_directions(mesh, x, y);
+            for y in 0..mesh.get_height() {
+                let directions = DownhillMap::compute_directions(mesh, self.neighbourhood, x, y);
                 self.set_directions(x, y, directions);
             }
         }
     }
 
-    fn cell_has_downhill(&self, x: i32, y: i32) -> bool {
+    pub fn get_candidates(&self, x: i32, y: i32) -> Vec<usize> {
+        self.get_directions(x, y)
+            .iter()
+            .enumerate()
+            .filter(|(_, downhill)| **downhill)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn cell_has_downhill(&self, x: i32, y: i32) -> bool {
         for downhill in self.get_directions(x, y).iter() {
             if *downhill {
                 return true;
@@ -65,7 +127,7 @@ impl DownhillMap {
 
     pub fn all_cells_have_downhill(&self) -> bool {
         for x in 0..self.width {
-            for y in 0..self.width {
+            for y in 0..self.height {
                 if !self.cell_has_downhill(x, y) {
                     return false;
                 }
@@ -89,12 +151,75 @@ mod tests {
             &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
         ));
 
-        let expected = [false, true, true, false];
-        let actual = DownhillMap::compute_directions(&mesh, 1, 1);
+        let expected = [false, true, true, false, false, false, false, false];
+        let actual = DownhillMap::compute_directions(&mesh, Neighbourhood::Four, 1, 1);
 
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_compute_directions_eight() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        let expected = [false, true, true, false, true, false, false, true];
+        let actual = DownhillMap::compute_directions(&mesh, Neighbourhood::Eight, 1, 1);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_get_directions_eight() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        let downhill = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+
+        assert_eq!(downhill.get_neighbourhood(), Neighbourhood::Eight);
+        assert_eq!(
+            downhill.get_directions(1, 1),
+            &[false, true, true, false, true, false, false, true]
+        );
+        assert_eq!(
+            DownhillMap::new(&mesh).get_directions(1, 1),
+            &[false, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_get_candidates() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        let downhill = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+
+        assert_eq!(downhill.get_candidates(1, 1), vec![1, 2, 4, 7]);
+    }
+
+    #[test]
+    fn test_get_slope() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.2, 0.6, 0.5, 0.8]));
+
+        assert_eq!(get_slope(&mesh, 1, 1, (0, -1)), 0.8 - 0.5);
+        assert_eq!(
+            get_slope(&mesh, 1, 1, (-1, -1)),
+            (0.8 - 0.2) / 2.0f64.sqrt()
+        );
+    }
+
     #[test]
     fn test_compute_all_directions() {
         let mut mesh = Mesh::new(2, 0.0);
@@ -102,14 +227,16 @@ mod tests {
 
         let expected = DownhillMap {
             width: 2,
+            height: 2,
+            neighbourhood: Neighbourhood::Four,
             directions: na::DMatrix::from_row_slice(
                 2,
                 2,
                 &[
-                    [true, true, false, false],
-                    [true, true, false, true],
-                    [true, true, true, false],
-                    [true, true, true, true],
+                    [true, true, false, false, false, false, false, false],
+                    [true, true, false, true, false, false, false, false],
+                    [true, true, true, false, false, false, false, false],
+                    [true, true, true, true, false, false, false, false],
                 ],
             ),
         };
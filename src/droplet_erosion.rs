@@ -0,0 +1,297 @@
+use erosion::ErosionResult;
+use mesh::Mesh;
+use rand::Rng;
+
+const GRAVITY: f64 = 4.0;
+
+struct Droplet {
+    position: (f64, f64),
+    direction: (f64, f64),
+    speed: f64,
+    water: f64,
+    sediment: f64,
+}
+
+pub struct DropletErosion {
+    inertia: f64,
+    capacity: f64,
+    min_slope: f64,
+    erosion: f64,
+    deposition: f64,
+    evaporation: f64,
+    max_steps: usize,
+}
+
+impl DropletErosion {
+    /// Droplets roll downhill, keeping `inertia` of their previous direction. A droplet can carry
+    /// `capacity * max(drop, min_slope) * speed * water` sediment. It picks up `erosion` of its
+    /// spare capacity each step (never more than it drops) and puts down `deposition` of any
+    /// excess, filling pits it cannot climb out of. A droplet loses `evaporation` of its water
+    /// each step and stops after `max_steps` or when it leaves the mesh.
+    pub fn new(
+        inertia: f64,
+        capacity: f64,
+        min_slope: f64,
+        erosion: f64,
+        deposition: f64,
+        evaporation: f64,
+        max_steps: usize,
+    ) -> DropletErosion {
+        DropletErosion {
+            inertia,
+            capacity,
+            min_slope,
+            erosion,
+            deposition,
+            evaporation,
+            max_steps,
+        }
+    }
+
+    pub fn erode<R: Rng>(
+        &self,
+        mut mesh: Mesh,
+        rng: &mut Box<R>,
+        droplets: usize,
+    ) -> ErosionResult {
+        let mut result = ErosionResult::new(mesh.clone());
+        let max = (
+            (mesh.get_width() - 1) as f64,
+            (mesh.get_height() - 1) as f64,
+        );
+        if max.0 <= 0.0 || max.1 <= 0.0 {
+            return result;
+        }
+        for _ in 0..droplets {
+            let position = (rng.gen_range(0.0, max.0), rng.gen_range(0.0, max.1));
+            self.run_droplet(&mut mesh, &mut result, position);
+        }
+        result.set_mesh(mesh);
+        result
+    }
+
+    fn in_bounds(mesh: &Mesh, position: (f64, f64)) -> bool {
+        let max = (
+            (mesh.get_width() - 1) as f64,
+            (mesh.get_height() - 1) as f64,
+        );
+        position.0 >= 0.0 && position.1 >= 0.0 && position.0 < max.0 && position.1 < max.1
+    }
+
+    // Cell (x, y) is at position (x, y), so a position inside the mesh is surrounded by four
+    // cells. Returns the top left cell and the bilinear weight of each of the four.
+    fn get_cells(position: (f64, f64)) -> ((i32, i32), [((i32, i32), f64); 4]) {
+        let (x, y) = (position.0.floor() as i32, position.1.floor() as i32);
+        let (u, v) = (position.0 - x as f64, position.1 - y as f64);
+        (
+            (x, y),
+            [
+                ((x, y), (1.0 - u) * (1.0 - v)),
+                ((x + 1, y), u * (1.0 - v)),
+                ((x, y + 1), (1.0 - u) * v),
+                ((x + 1, y + 1), u * v),
+            ],
+        )
+    }
+
+    fn get_height_and_gradient(mesh: &Mesh, position: (f64, f64)) -> (f64, (f64, f64)) {
+        let ((x, y), _) = DropletErosion::get_cells(position);
+        let (u, v) = (position.0 - x as f64, position.1 - y as f64);
+        let z00 = mesh.get_z(x, y);
+        let z10 = mesh.get_z(x + 1, y);
+        let z01 = mesh.get_z(x, y + 1);
+        let z11 = mesh.get_z(x + 1, y + 1);
+        let height =
+            z00 * (1.0 - u) * (1.0 - v) + z10 * u * (1.0 - v) + z01 * (1.0 - u) * v + z11 * u * v;
+        let gradient = (
+            (z10 - z00) * (1.0 - v) + (z11 - z01) * v,
+            (z01 - z00) * (1.0 - u) + (z11 - z10) * u,
+        );
+        (height, gradient)
+    }
+
+    fn change_z(mesh: &mut Mesh, result: &mut ErosionResult, position: (f64, f64), amount: f64) {
+        let (_, cells) = DropletErosion::get_cells(position);
+        for ((x, y), weight) in cells.iter() {
+            let z = mesh.get_z(*x, *y);
+            mesh.set_z(*x, *y, z + amount * weight);
+            result.record_change(*x, *y, amount * weight);
+        }
+    }
+
+    fn record_sediment(result: &mut ErosionResult, position: (f64, f64), amount: f64) {
+        let (_, cells) = DropletErosion::get_cells(position);
+        for ((x, y), weight) in cells.iter() {
+            result.record_sediment(*x, *y, amount * weight);
+        }
+    }
+
+    fn run_droplet(&self, mesh: &mut Mesh, result: &mut ErosionResult, position: (f64, f64)) {
+        let mut droplet = Droplet {
+            position,
+            direction: (0.0, 0.0),
+            speed: 1.0,
+            water: 1.0,
+            sediment: 0.0,
+        };
+        for _ in 0..self.max_steps {
+            let (height, gradient) =
+                DropletErosion::get_height_and_gradient(mesh, droplet.position);
+
+            let direction = (
+                droplet.direction.0 * self.inertia - gradient.0 * (1.0 - self.inertia),
+                droplet.direction.1 * self.inertia - gradient.1 * (1.0 - self.inertia),
+            );
+            let length = (direction.0 * direction.0 + direction.1 * direction.1).sqrt();
+            if length == 0.0 {
+                return;
+            }
+            droplet.direction = (direction.0 / length, direction.1 / length);
+
+            let old_position = droplet.position;
+            droplet.position = (
+                old_position.0 + droplet.direction.0,
+                old_position.1 + droplet.direction.1,
+            );
+            if !DropletErosion::in_bounds(mesh, droplet.position) {
+                return;
+            }
+
+            let (new_height, _) = DropletErosion::get_height_and_gradient(mesh, droplet.position);
+            let drop = height - new_height;
+            let capacity = drop.max(self.min_slope) * droplet.speed * droplet.water * self.capacity;
+
+            if drop < 0.0 {
+                let deposit = droplet.sediment.min(-drop);
+                droplet.sediment -= deposit;
+                DropletErosion::change_z(mesh, result, old_position, deposit);
+            } else if droplet.sediment > capacity {
+                let deposit = (droplet.sediment - capacity) * self.deposition;
+                droplet.sediment -= deposit;
+                DropletErosion::change_z(mesh, result, old_position, deposit);
+            } else {
+                let erosion = ((capacity - droplet.sediment) * self.erosion).min(drop);
+                droplet.sediment += erosion;
+                DropletErosion::change_z(mesh, result, old_position, -erosion);
+            }
+            DropletErosion::record_sediment(result, old_position, droplet.sediment);
+
+            droplet.speed = (droplet.speed * droplet.speed + drop * GRAVITY)
+                .max(0.0)
+                .sqrt();
+            droplet.water *= 1.0 - self.evaporation;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use mesh_splitter::MeshSplitter;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn erosion() -> DropletErosion {
+        DropletErosion::new(0.05, 4.0, 0.01, 0.3, 0.3, 0.01, 30)
+    }
+
+    fn mesh() -> Mesh {
+        let mut rng = Box::new(StdRng::seed_from_u64(1));
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 5)
+    }
+
+    fn total(mesh: &Mesh) -> f64 {
+        mesh.get_z_vector().iter().sum()
+    }
+
+    #[test]
+    fn test_get_height_and_gradient() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.5]));
+
+        let (height, gradient) = DropletErosion::get_height_and_gradient(&mesh, (0.5, 0.25));
+
+        assert!((height - 0.325).abs() < 1e-9);
+        assert!((gradient.0 - -0.1).abs() < 1e-9);
+        assert!((gradient.1 - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_change_z() {
+        let mut mesh = Mesh::new(2, 0.0);
+        let mut result = ErosionResult::new(mesh.clone());
+
+        DropletErosion::change_z(&mut mesh, &mut result, (0.5, 0.25), -1.0);
+
+        let expected = na::DMatrix::from_row_slice(2, 2, &[0.375, 0.125, 0.375, 0.125]);
+        for (a, e) in mesh.get_z_vector().iter().zip(expected.iter()) {
+            assert!((a + e).abs() < 1e-9);
+        }
+        for (a, e) in result.get_erosion().iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9);
+        }
+        assert_eq!(result.get_deposition(), &na::DMatrix::zeros(2, 2));
+    }
+
+    #[test]
+    fn same_seed_should_give_same_mesh() {
+        let a = erosion().erode(mesh(), &mut Box::new(StdRng::seed_from_u64(7)), 500);
+        let b = erosion().erode(mesh(), &mut Box::new(StdRng::seed_from_u64(7)), 500);
+
+        assert_eq!(a, b);
+        assert_ne!(a.get_mesh(), &mesh());
+    }
+
+    #[test]
+    fn flat_mesh_should_not_change() {
+        let mut rng = Box::new(StdRng::seed_from_u64(7));
+        let mesh = Mesh::new(8, 0.0);
+
+        assert_eq!(
+            erosion().erode(mesh.clone(), &mut rng, 100).get_mesh(),
+            &mesh
+        );
+    }
+
+    #[test]
+    fn droplets_should_not_create_material() {
+        let mut rng = Box::new(StdRng::seed_from_u64(7));
+        let before = mesh();
+
+        let after = erosion().erode(before.clone(), &mut rng, 1000);
+
+        assert!(total(after.get_mesh()) <= total(&before) + 1e-9);
+        let eroded: f64 = after.get_erosion().iter().sum();
+        let deposited: f64 = after.get_deposition().iter().sum();
+        assert!(deposited <= eroded + 1e-9);
+    }
+
+    #[test]
+    fn droplets_should_erode_slope_and_deposit_in_pit() {
+        let mut mesh = Mesh::new(9, 0.0);
+        for x in 0..9 {
+            for y in 0..9 {
+                mesh.set_z(x, y, x as f64 * 0.1);
+            }
+        }
+        for y in 0..9 {
+            mesh.set_z(0, y, 0.05);
+            mesh.set_z(1, y, 0.0);
+        }
+        let mut rng = Box::new(StdRng::seed_from_u64(7));
+
+        let actual = erosion().erode(mesh.clone(), &mut rng, 200);
+
+        let after = actual.get_mesh();
+        let eroded = (3..8).any(|x| (0..9).any(|y| after.get_z(x, y) < mesh.get_z(x, y)));
+        let deposited = (0..9).any(|y| after.get_z(1, y) > 0.0);
+        assert!(eroded);
+        assert!(deposited);
+        assert!((0..9).any(|y| actual.get_deposition()[(1, y)] > 0.0));
+        assert!((3..8).any(|x| actual.get_sediment()[(x, 4)] > 0.0));
+    }
+
+}
@@ -0,0 +1,187 @@
+/// What lies beyond one edge of a mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Boundary {
+    /// Cells at this height, which water can flow off the mesh into.
+    Constant(f64),
+    /// The nearest edge cell.
+    Clamp,
+    /// Reflection of the mesh about the edge cells.
+    Mirror,
+    /// The opposite edge, which must also wrap.
+    Wrap,
+}
+
+/// Where a position off the mesh leads to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    Cell(i32, i32),
+    Exit(f64),
+}
+
+/// Boundaries for each edge of a mesh. West is below x = 0, north below y = 0, east above the
+/// last x and south above the last y.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EdgePolicy {
+    west: Boundary,
+    north: Boundary,
+    east: Boundary,
+    south: Boundary,
+}
+
+impl EdgePolicy {
+    pub fn new(west: Boundary, north: Boundary, east: Boundary, south: Boundary) -> EdgePolicy {
+        assert!(
+            (west == Boundary::Wrap) == (east == Boundary::Wrap),
+            "West and east edges must both wrap or neither wrap"
+        );
+        assert!(
+            (north == Boundary::Wrap) == (south == Boundary::Wrap),
+            "North and south edges must both wrap or neither wrap"
+        );
+        EdgePolicy {
+            west,
+            north,
+            east,
+            south,
+        }
+    }
+
+    pub fn all(boundary: Boundary) -> EdgePolicy {
+        EdgePolicy::new(boundary, boundary, boundary, boundary)
+    }
+
+    pub fn get_west(&self) -> Boundary {
+        self.west
+    }
+
+    pub fn get_north(&self) -> Boundary {
+        self.north
+    }
+
+    pub fn get_east(&self) -> Boundary {
+        self.east
+    }
+
+    pub fn get_south(&self) -> Boundary {
+        self.south
+    }
+
+    pub fn is_constant(&self) -> bool {
+        [self.west, self.north, self.east, self.south]
+            .iter()
+            .all(|boundary| match boundary {
+                Boundary::Constant(_) => true,
+                _ => false,
+            })
+    }
+
+    // Ok with the coordinate of a cell, or Err with the height of an exit
+    fn locate_axis(value: i32, size: i32, low: Boundary, high: Boundary) -> Result<i32, f64> {
+        let boundary = if value < 0 {
+            low
+        } else if value >= size {
+            high
+        } else {
+            return Ok(value);
+        };
+        match boundary {
+            Boundary::Constant(z) => Err(z),
+            Boundary::Clamp => Ok(value.max(0).min(size - 1)),
+            Boundary::Mirror => {
+                if size == 1 {
+                    return Ok(0);
+                }
+                let period = 2 * (size - 1);
+                let value = ((value % period) + period) % period;
+                Ok(if value < size { value } else { period - value })
+            }
+            Boundary::Wrap => Ok(((value % size) + size) % size),
+        }
+    }
+
+    /// Where (`x`, `y`) leads on a mesh of the given size. Positions off a corner are resolved
+    /// along x first, so a constant west or east edge takes precedence over north and south.
+    pub fn locate(&self, x: i32, y: i32, width: i32, height: i32) -> Location {
+        let x = match EdgePolicy::locate_axis(x, width, self.west, self.east) {
+            Ok(x) => x,
+            Err(z) => return Location::Exit(z),
+        };
+        match EdgePolicy::locate_axis(y, height, self.north, self.south) {
+            Ok(y) => Location::Cell(x, y),
+            Err(z) => Location::Exit(z),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_locate_constant() {
+        let policy = EdgePolicy::new(
+            Boundary::Constant(0.0),
+            Boundary::Constant(1.0),
+            Boundary::Constant(2.0),
+            Boundary::Constant(3.0),
+        );
+
+        assert_eq!(policy.locate(1, 1, 3, 2), Location::Cell(1, 1));
+        assert_eq!(policy.locate(-1, 1, 3, 2), Location::Exit(0.0));
+        assert_eq!(policy.locate(1, -1, 3, 2), Location::Exit(1.0));
+        assert_eq!(policy.locate(3, 1, 3, 2), Location::Exit(2.0));
+        assert_eq!(policy.locate(1, 2, 3, 2), Location::Exit(3.0));
+        assert_eq!(policy.locate(3, 2, 3, 2), Location::Exit(2.0));
+        assert_eq!(policy.is_constant(), true);
+    }
+
+    #[test]
+    fn test_locate_clamp() {
+        let policy = EdgePolicy::all(Boundary::Clamp);
+
+        assert_eq!(policy.locate(-1, 1, 3, 2), Location::Cell(0, 1));
+        assert_eq!(policy.locate(3, 2, 3, 2), Location::Cell(2, 1));
+        assert_eq!(policy.is_constant(), false);
+    }
+
+    #[test]
+    fn test_locate_mirror() {
+        let policy = EdgePolicy::all(Boundary::Mirror);
+
+        assert_eq!(policy.locate(-1, 0, 3, 2), Location::Cell(1, 0));
+        assert_eq!(policy.locate(3, 0, 3, 2), Location::Cell(1, 0));
+        assert_eq!(policy.locate(-2, -1, 3, 2), Location::Cell(2, 1));
+        assert_eq!(policy.locate(-1, -1, 1, 1), Location::Cell(0, 0));
+    }
+
+    #[test]
+    fn test_locate_wrap() {
+        let policy = EdgePolicy::new(
+            Boundary::Wrap,
+            Boundary::Constant(0.5),
+            Boundary::Wrap,
+            Boundary::Clamp,
+        );
+
+        assert_eq!(policy.locate(-1, 1, 3, 2), Location::Cell(2, 1));
+        assert_eq!(policy.locate(3, 1, 3, 2), Location::Cell(0, 1));
+        assert_eq!(policy.locate(-4, 1, 3, 2), Location::Cell(2, 1));
+        assert_eq!(policy.locate(-1, -1, 3, 2), Location::Exit(0.5));
+        assert_eq!(policy.locate(3, 2, 3, 2), Location::Cell(0, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "West and east edges must both wrap or neither wrap")]
+    fn should_reject_one_sided_wrap() {
+        EdgePolicy::new(
+            Boundary::Wrap,
+            Boundary::Clamp,
+            Boundary::Clamp,
+            Boundary::Clamp,
+        );
+    }
+
+}
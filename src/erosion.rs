@@ -1,32 +1,355 @@
 use downhill_map::DownhillMap;
 use flow_map::FlowMap;
 use mesh::Mesh;
+use multiple_flow_map::MultipleFlowMap;
 use rand::prelude::*;
-use single_downhill_map::{RandomDownhillMap, SingleDownhillMap};
+use single_downhill_map::{RandomDownhillMap, SingleDownhillMap, WeightedDownhillMap};
+use std::f64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErosionResult {
+    mesh: Mesh,
+    erosion: na::DMatrix<f64>,
+    deposition: na::DMatrix<f64>,
+    sediment: na::DMatrix<f64>,
+}
+
+impl ErosionResult {
+    /// `erosion` and `deposition` hold the total material removed from and added to each cell.
+    /// `sediment` holds the total material carried out of each cell, including material eroded
+    /// from the cell itself.
+    pub fn new(mesh: Mesh) -> ErosionResult {
+        let (width, height) = mesh.get_shape();
+        ErosionResult {
+            mesh,
+            erosion: na::DMatrix::zeros(width, height),
+            deposition: na::DMatrix::zeros(width, height),
+            sediment: na::DMatrix::zeros(width, height),
+        }
+    }
+
+    pub fn get_mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    pub fn set_mesh(&mut self, mesh: Mesh) {
+        self.mesh = mesh;
+    }
+
+    pub fn get_erosion(&self) -> &na::DMatrix<f64> {
+        &self.erosion
+    }
+
+    pub fn get_deposition(&self) -> &na::DMatrix<f64> {
+        &self.deposition
+    }
+
+    pub fn get_sediment(&self) -> &na::DMatrix<f64> {
+        &self.sediment
+    }
+
+    /// Records a change in height of a cell as erosion if negative or deposition if positive.
+    /// The mesh itself is not changed.
+    pub fn record_change(&mut self, x: i32, y: i32, change: f64) {
+        let cell = (x as usize, y as usize);
+        if change < 0.0 {
+            self.erosion[cell] -= change;
+        } else {
+            self.deposition[cell] += change;
+        }
+    }
+
+    pub fn record_sediment(&mut self, x: i32, y: i32, amount: f64) {
+        self.sediment[(x as usize, y as usize)] += amount;
+    }
+
+    pub fn record_all_sediment(&mut self, sediment: &na::DMatrix<f64>) {
+        self.sediment += sediment;
+    }
+}
+
+pub struct ErosionConfig {
+    strength: Box<Fn(f64) -> f64>,
+    max_erosion: f64,
+    min_flow: f64,
+    repeat: bool,
+    sea_level: f64,
+}
+
+impl ErosionConfig {
+    /// A cell with flow above `min_flow` loses `strength(flow)` of its height each time it is
+    /// eroded, but never more than `max_erosion` in total and never below `sea_level`. Cells
+    /// already at or below `sea_level` are not eroded. Unless `repeat` is set, a cell is only
+    /// eroded once however many samples are taken.
+    pub fn new<F>(
+        strength: F,
+        max_erosion: f64,
+        min_flow: f64,
+        repeat: bool,
+        sea_level: f64,
+    ) -> ErosionConfig
+    where
+        F: Fn(f64) -> f64 + 'static,
+    {
+        ErosionConfig {
+            strength: Box::new(strength),
+            max_erosion,
+            min_flow,
+            repeat,
+            sea_level,
+        }
+    }
+
+    /// Removes a tenth of the height of each cell with flow above `threshold` (and above 1),
+    /// at most once per cell.
+    pub fn with_threshold(threshold: f64) -> ErosionConfig {
+        ErosionConfig::new(
+            |_| 0.1,
+            f64::INFINITY,
+            threshold.max(1.0),
+            false,
+            f64::NEG_INFINITY,
+        )
+    }
+
+    pub fn get_strength(&self, flow: f64) -> f64 {
+        (self.strength)(flow)
+    }
+
+    pub fn get_max_erosion(&self) -> f64 {
+        self.max_erosion
+    }
+
+    pub fn get_min_flow(&self) -> f64 {
+        self.min_flow
+    }
+
+    pub fn get_repeat(&self) -> bool {
+        self.repeat
+    }
+
+    pub fn get_sea_level(&self) -> f64 {
+        self.sea_level
+    }
+
+    pub fn get_erosion(&self, z: f64, flow: f64, eroded: f64) -> f64 {
+        if flow <= self.min_flow || z <= self.sea_level {
+            return 0.0;
+        }
+        (z * (self.strength)(flow))
+            .min(self.max_erosion - eroded)
+            .min(z - self.sea_level)
+            .max(0.0)
+    }
+}
 
 pub struct Erosion {}
 
 impl Erosion {
-    pub fn erode<R: Rng>(mut mesh: Mesh, rng: &mut Box<R>, threshold: u32, samples: usize) -> Mesh {
+    pub fn erode<R: Rng>(
+        mesh: Mesh,
+        rng: &mut Box<R>,
+        config: &ErosionConfig,
+        samples: usize,
+    ) -> ErosionResult {
+        Erosion::erode_with(mesh, rng, config, samples, |_, downhill_map, rng| {
+            Box::new(RandomDownhillMap::new(downhill_map, rng))
+        })
+    }
+
+    pub fn erode_weighted<R: Rng>(
+        mesh: Mesh,
+        rng: &mut Box<R>,
+        config: &ErosionConfig,
+        samples: usize,
+        exponent: f64,
+    ) -> ErosionResult {
+        Erosion::erode_with(mesh, rng, config, samples, |mesh, downhill_map, rng| {
+            Box::new(WeightedDownhillMap::new(mesh, downhill_map, exponent, rng))
+        })
+    }
+
+    pub fn erode_multiple_flow(
+        mut mesh: Mesh,
+        config: &ErosionConfig,
+        exponent: f64,
+    ) -> ErosionResult {
+        let mut result = ErosionResult::new(mesh.clone());
+        let downhill_map = DownhillMap::new(&mesh);
+        let flow_map = MultipleFlowMap::from(&mesh, &downhill_map, exponent);
+        let (width, height) = mesh.get_shape();
+        let mut eroded = na::DMatrix::zeros(width, height);
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                let before = mesh.get_z(x, y);
+                let erosion = config.get_erosion(before, flow_map.get_flow(x, y), 0.0);
+                if erosion > 0.0 {
+                    mesh.set_z(x, y, before - erosion);
+                    result.record_change(x, y, -erosion);
+                    eroded[(x as usize, y as usize)] = erosion;
+                }
+            }
+        }
+        let sediment = MultipleFlowMap::from_precipitation(
+            result.get_mesh(),
+            &downhill_map,
+            exponent,
+            &eroded,
+        );
+        result.record_all_sediment(sediment.get_flow_matrix());
+        result.set_mesh(mesh);
+        result
+    }
+
+    fn erode_with<R: Rng, F>(
+        mut mesh: Mesh,
+        rng: &mut Box<R>,
+        config: &ErosionConfig,
+        samples: usize,
+        single_downhill_map: F,
+    ) -> ErosionResult
+    where
+        F: Fn(&Mesh, &DownhillMap, &mut Box<R>) -> Box<SingleDownhillMap>,
+    {
+        let original = mesh.clone();
+        let mut result = ErosionResult::new(mesh.clone());
         let downhill_map = DownhillMap::new(&mesh);
-        let mut eroded = vec![vec![false; mesh.get_width() as usize]; mesh.get_width() as usize];
+        let (width, height) = mesh.get_shape();
+        let mut eroded = vec![vec![false; height]; width];
         for _ in 0..samples {
-            let random_downhill_map = RandomDownhillMap::new(&downhill_map, rng);
-            let random_downhill_map: Box<SingleDownhillMap> = Box::new(random_downhill_map);
-            let flow_map = FlowMap::from(&mesh, &random_downhill_map);
+            let random_downhill_map = single_downhill_map(&original, &downhill_map, rng);
+            let flow_map = FlowMap::from_topological(&mesh, &random_downhill_map);
+            let mut sample_erosion = na::DMatrix::zeros(width, height);
             for x in 0..mesh.get_width() {
-                for y in 0..mesh.get_width() {
-                    if !eroded[x as usize][y as usize] {
-                        let flow = flow_map.get_flow(x, y);
-                        if flow > 1 && flow > threshold {
-                            let after = mesh.get_z(x, y) * 0.9;
-                            mesh.set_z(x, y, after);
+                for y in 0..mesh.get_height() {
+                    if config.repeat || !eroded[x as usize][y as usize] {
+                        let before = mesh.get_z(x, y);
+                        let so_far = result.get_erosion()[(x as usize, y as usize)];
+                        let erosion = config.get_erosion(before, flow_map.get_flow(x, y), so_far);
+                        if erosion > 0.0 {
+                            mesh.set_z(x, y, before - erosion);
                             eroded[x as usize][y as usize] = true;
+                            result.record_change(x, y, -erosion);
+                            sample_erosion[(x as usize, y as usize)] = erosion;
                         }
                     }
                 }
             }
+            let sediment =
+                FlowMap::from_precipitation(&mesh, &random_downhill_map, &sample_erosion);
+            result.record_all_sediment(sediment.get_flow_matrix());
+        }
+        result.set_mesh(mesh);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::rngs::mock::StepRng;
+    use single_downhill_map::MockDownhillMap;
+
+    fn erode_mock(config: &ErosionConfig, samples: usize) -> ErosionResult {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.1]));
+        let mut rng = Box::new(StepRng::new(0, 0));
+        Erosion::erode_with(mesh, &mut rng, config, samples, |_, _, _| {
+            Box::new(MockDownhillMap::new(vec![vec![2, 2], vec![3, 2]]))
+        })
+    }
+
+    fn assert_z_eq(actual: &ErosionResult, expected: &[f64]) {
+        let expected = na::DMatrix::from_row_slice(2, 2, expected);
+        for (a, e) in actual.get_mesh().get_z_vector().iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9, "{} != {}", a, e);
+        }
+    }
+
+    #[test]
+    fn test_erode_with_threshold() {
+        let actual = erode_mock(&ErosionConfig::with_threshold(3.0), 3);
+
+        assert_z_eq(&actual, &[0.4, 0.3, 0.2, 0.09]);
+    }
+
+    #[test]
+    fn test_erode_repeat() {
+        let config = ErosionConfig::new(|_| 0.5, f64::INFINITY, 1.0, true, f64::NEG_INFINITY);
+
+        assert_z_eq(&erode_mock(&config, 2), &[0.4, 0.3, 0.05, 0.025]);
+    }
+
+    #[test]
+    fn test_erode_no_repeat() {
+        let config = ErosionConfig::new(|_| 0.5, f64::INFINITY, 1.0, false, f64::NEG_INFINITY);
+
+        assert_z_eq(&erode_mock(&config, 2), &[0.4, 0.3, 0.1, 0.05]);
+    }
+
+    #[test]
+    fn test_erode_max_erosion() {
+        let config = ErosionConfig::new(|_| 0.5, 0.06, 1.0, true, f64::NEG_INFINITY);
+
+        let actual = erode_mock(&config, 2);
+
+        assert_z_eq(&actual, &[0.4, 0.3, 0.14, 0.04]);
+        assert!(actual
+            .get_erosion()
+            .iter()
+            .all(|erosion| *erosion <= 0.06 + 1e-9));
+    }
+
+    #[test]
+    fn test_erode_sea_level() {
+        let config = ErosionConfig::new(|_| 0.5, f64::INFINITY, 1.0, false, 0.15);
+
+        assert_z_eq(&erode_mock(&config, 1), &[0.4, 0.3, 0.15, 0.1]);
+    }
+
+    #[test]
+    fn test_erode_strength_from_flow() {
+        let config = ErosionConfig::new(|flow| flow / 10.0, f64::INFINITY, 1.0, false, 0.0);
+
+        assert_z_eq(&erode_mock(&config, 1), &[0.4, 0.3, 0.16, 0.06]);
+    }
+
+    #[test]
+    fn test_record_change() {
+        let mut result = ErosionResult::new(Mesh::new(2, 0.0));
+
+        result.record_change(0, 1, -0.5);
+        result.record_change(0, 1, 0.2);
+        result.record_change(1, 0, 0.3);
+
+        assert_eq!(
+            result.get_erosion(),
+            &na::DMatrix::from_row_slice(2, 2, &[0.0, 0.5, 0.0, 0.0])
+        );
+        assert_eq!(
+            result.get_deposition(),
+            &na::DMatrix::from_row_slice(2, 2, &[0.0, 0.2, 0.3, 0.0])
+        );
+    }
+
+    #[test]
+    fn test_erode_multiple_flow_result() {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.1]));
+
+        let actual = Erosion::erode_multiple_flow(mesh, &ErosionConfig::with_threshold(1.5), 1.0);
+
+        let expected_erosion = na::DMatrix::from_row_slice(2, 2, &[0.0, 0.0, 0.02, 0.01]);
+        let expected_sediment = na::DMatrix::from_row_slice(2, 2, &[0.0, 0.0, 0.02, 0.03]);
+        for (a, e) in actual.get_erosion().iter().zip(expected_erosion.iter()) {
+            assert!((a - e).abs() < 1e-9);
         }
-        mesh
+        for (a, e) in actual.get_sediment().iter().zip(expected_sediment.iter()) {
+            assert!((a - e).abs() < 1e-9);
+        }
+        assert_eq!(actual.get_deposition(), &na::DMatrix::zeros(2, 2));
+        assert!((actual.get_mesh().get_z(1, 0) - 0.18).abs() < 1e-9);
     }
+
 }
@@ -1,54 +1,149 @@
-use downhill_map::DIRECTIONS;
+use downhill_map::ALL_DIRECTIONS;
 use mesh::Mesh;
 use single_downhill_map::SingleDownhillMap;
+use std::collections::VecDeque;
+use utils::float_ordering;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FlowMap {
-    flow: na::DMatrix<u32>,
+    flow: na::DMatrix<f64>,
 }
 
 impl FlowMap {
     pub fn new(width: usize) -> FlowMap {
+        FlowMap::with_size(width, width)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> FlowMap {
         FlowMap {
-            flow: na::DMatrix::zeros(width, width),
+            flow: na::DMatrix::zeros(width, height),
         }
     }
 
-    pub fn get_flow(&self, x: i32, y: i32) -> u32 {
+    pub fn get_flow(&self, x: i32, y: i32) -> f64 {
         self.flow[(x as usize, y as usize)]
     }
 
-    pub fn get_flow_matrix(&self) -> &na::DMatrix<u32> {
+    pub fn get_flow_matrix(&self) -> &na::DMatrix<f64> {
         &self.flow
     }
 
-    pub fn get_max_flow(&self) -> u32 {
-        *self.flow.iter().max().unwrap()
+    pub fn get_max_flow(&self) -> f64 {
+        *self.flow.iter().max_by(float_ordering).unwrap()
     }
 
-    pub fn set_flow(&mut self, flow: na::DMatrix<u32>) {
+    pub fn set_flow(&mut self, flow: na::DMatrix<f64>) {
         self.flow = flow;
     }
 
+    fn uniform_precipitation(mesh: &Mesh) -> na::DMatrix<f64> {
+        let (width, height) = mesh.get_shape();
+        na::DMatrix::repeat(width, height, 1.0)
+    }
+
     pub fn from(mesh: &Mesh, downhill_map: &Box<SingleDownhillMap>) -> FlowMap {
-        let mut out = FlowMap::new(mesh.get_width() as usize);
-        out.rain_on_all(mesh, downhill_map);
+        let (width, height) = mesh.get_shape();
+        let mut out = FlowMap::with_size(width, height);
+        out.rain_on_all(mesh, downhill_map, &FlowMap::uniform_precipitation(mesh));
+        out
+    }
+
+    pub fn from_topological(mesh: &Mesh, downhill_map: &Box<SingleDownhillMap>) -> FlowMap {
+        FlowMap::from_precipitation(mesh, downhill_map, &FlowMap::uniform_precipitation(mesh))
+    }
+
+    /// Each cell receives the amount of water in the corresponding cell of `precipitation`,
+    /// which must be the same size as the mesh.
+    pub fn from_precipitation(
+        mesh: &Mesh,
+        downhill_map: &Box<SingleDownhillMap>,
+        precipitation: &na::DMatrix<f64>,
+    ) -> FlowMap {
+        assert_eq!(
+            precipitation.shape(),
+            mesh.get_shape(),
+            "Precipitation must be the same size as the mesh"
+        );
+        let (width, height) = mesh.get_shape();
+        let mut out = FlowMap::with_size(width, height);
+        out.accumulate_all(mesh, downhill_map, precipitation);
         out
     }
 
-    fn rain_on(&mut self, mesh: &Mesh, downhill_map: &Box<SingleDownhillMap>, x: i32, y: i32) {
-        let mut focus = (x, y);
-        while mesh.in_bounds(focus.0, focus.1) {
-            self.flow[(focus.0 as usize, focus.1 as usize)] += 1;
-            let direction = DIRECTIONS[downhill_map.get_direction(focus.0, focus.1)];
-            focus = (focus.0 + direction.0, focus.1 + direction.1);
+    fn get_downhill_cell(
+        mesh: &Mesh,
+        downhill_map: &Box<SingleDownhillMap>,
+        x: i32,
+        y: i32,
+    ) -> Option<(i32, i32)> {
+        let direction = ALL_DIRECTIONS[downhill_map.get_direction(x, y)];
+        mesh.get_neighbour(x, y, direction)
+    }
+
+    fn rain_on(
+        &mut self,
+        mesh: &Mesh,
+        downhill_map: &Box<SingleDownhillMap>,
+        x: i32,
+        y: i32,
+        amount: f64,
+    ) {
+        let mut focus = Some((x, y));
+        while let Some((x, y)) = focus {
+            self.flow[(x as usize, y as usize)] += amount;
+            focus = FlowMap::get_downhill_cell(mesh, downhill_map, x, y);
         }
     }
 
-    fn rain_on_all(&mut self, mesh: &Mesh, downhill_map: &Box<SingleDownhillMap>) {
+    fn rain_on_all(
+        &mut self,
+        mesh: &Mesh,
+        downhill_map: &Box<SingleDownhillMap>,
+        precipitation: &na::DMatrix<f64>,
+    ) {
         for x in 0..mesh.get_width() {
-            for y in 0..mesh.get_width() {
-                self.rain_on(mesh, downhill_map, x, y);
+            for y in 0..mesh.get_height() {
+                let amount = precipitation[(x as usize, y as usize)];
+                self.rain_on(mesh, downhill_map, x, y, amount);
+            }
+        }
+    }
+
+    fn accumulate_all(
+        &mut self,
+        mesh: &Mesh,
+        downhill_map: &Box<SingleDownhillMap>,
+        precipitation: &na::DMatrix<f64>,
+    ) {
+        let (width, height) = mesh.get_shape();
+        let mut upstream = na::DMatrix::<u32>::zeros(width, height);
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                self.flow[(x as usize, y as usize)] = precipitation[(x as usize, y as usize)];
+                if let Some((nx, ny)) = FlowMap::get_downhill_cell(mesh, downhill_map, x, y) {
+                    upstream[(nx as usize, ny as usize)] += 1;
+                }
+            }
+        }
+
+        let mut sources: VecDeque<(i32, i32)> = VecDeque::with_capacity(width * height);
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                if upstream[(x as usize, y as usize)] == 0 {
+                    sources.push_back((x, y));
+                }
+            }
+        }
+
+        while let Some((x, y)) = sources.pop_front() {
+            if let Some((nx, ny)) = FlowMap::get_downhill_cell(mesh, downhill_map, x, y) {
+                let (nx, ny) = (nx as usize, ny as usize);
+                self.flow[(nx, ny)] += self.flow[(x as usize, y as usize)];
+                upstream[(nx, ny)] -= 1;
+                if upstream[(nx, ny)] == 0 {
+                    sources.push_back((nx as i32, ny as i32));
+                }
             }
         }
     }
@@ -74,10 +169,15 @@ mod tests {
         let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
 
         let mut flow_map = FlowMap::new(4);
-        flow_map.rain_on(&mesh, &downhill_map, 2, 1);
+        flow_map.rain_on(&mesh, &downhill_map, 2, 1, 1.0);
 
-        let expected =
-            na::DMatrix::from_row_slice(4, 4, &[0, 0, 0, 0, 0, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let expected = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+            ],
+        );
         let expected = FlowMap { flow: expected };
 
         assert_eq!(flow_map, expected);
@@ -98,11 +198,169 @@ mod tests {
 
         let flow_map = FlowMap::from(&mesh, &downhill_map);
 
+        let expected = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1.0, 2.0, 3.0, 4.0, 3.0, 6.0, 9.0, 12.0, 2.0, 2.0, 2.0, 2.0, 1.0, 1.0, 1.0, 1.0,
+            ],
+        );
+        let expected = FlowMap { flow: expected };
+
+        assert_eq!(flow_map, expected);
+    }
+
+    #[test]
+    pub fn test_from_topological() {
+        let mesh = Mesh::new(4, 0.0);
+
+        let directions = vec![
+            vec![3, 3, 3, 3],
+            vec![3, 3, 3, 3],
+            vec![0, 0, 0, 0],
+            vec![0, 0, 0, 0],
+        ];
+        let downhill_map = MockDownhillMap::new(directions);
+        let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
+
+        let flow_map = FlowMap::from_topological(&mesh, &downhill_map);
+
+        let expected = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1.0, 2.0, 3.0, 4.0, 3.0, 6.0, 9.0, 12.0, 2.0, 2.0, 2.0, 2.0, 1.0, 1.0, 1.0, 1.0,
+            ],
+        );
+        let expected = FlowMap { flow: expected };
+
+        assert_eq!(flow_map, expected);
+    }
+
+    #[test]
+    pub fn test_from_topological_rectangular() {
+        let mesh = Mesh::with_size(3, 2, 0.0);
+
+        let directions = vec![vec![2, 1], vec![2, 1], vec![2, 1]];
+        let downhill_map = MockDownhillMap::new(directions);
+        let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
+
+        let flow_map = FlowMap::from_topological(&mesh, &downhill_map);
+
+        let expected = na::DMatrix::from_row_slice(3, 2, &[2.0, 1.0, 4.0, 1.0, 6.0, 1.0]);
+        let expected = FlowMap { flow: expected };
+
+        assert_eq!(flow_map, expected);
+        assert_eq!(FlowMap::from(&mesh, &downhill_map), expected);
+    }
+
+    #[test]
+    pub fn test_from_wrapped() {
+        use edge_policy::{Boundary, EdgePolicy};
+
+        let edge_policy = EdgePolicy::new(
+            Boundary::Wrap,
+            Boundary::Constant(0.0),
+            Boundary::Wrap,
+            Boundary::Constant(0.0),
+        );
+        let mesh = Mesh::with_edge_policy(3, 2, edge_policy);
+
+        let directions = vec![vec![0, 0], vec![0, 0], vec![1, 3]];
+        let downhill_map = MockDownhillMap::new(directions);
+        let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
+
+        let expected = na::DMatrix::from_row_slice(3, 2, &[2.0, 2.0, 1.0, 1.0, 3.0, 3.0]);
+        let expected = FlowMap { flow: expected };
+
+        assert_eq!(FlowMap::from(&mesh, &downhill_map), expected);
+        assert_eq!(FlowMap::from_topological(&mesh, &downhill_map), expected);
+    }
+
+    #[test]
+    pub fn from_topological_should_match_from() {
+        use downhill_map::DownhillMap;
+        use mesh_splitter::MeshSplitter;
+        use single_downhill_map::RandomDownhillMap;
+
+        let mut rng = Box::new(rand::thread_rng());
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        let mesh = MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 6);
+
+        let downhill_map = DownhillMap::new(&mesh);
+        let random_downhill_map = RandomDownhillMap::new(&downhill_map, &mut rng);
+        let random_downhill_map: Box<SingleDownhillMap> = Box::new(random_downhill_map);
+
+        assert_eq!(
+            FlowMap::from_topological(&mesh, &random_downhill_map),
+            FlowMap::from(&mesh, &random_downhill_map)
+        );
+    }
+
+    #[test]
+    pub fn test_from_diagonal() {
+        let mesh = Mesh::new(3, 0.0);
+
+        let directions = vec![vec![0, 0, 0], vec![0, 4, 0], vec![0, 0, 4]];
+        let downhill_map = MockDownhillMap::new(directions);
+        let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
+
         let expected =
-            na::DMatrix::from_row_slice(4, 4, &[1, 2, 3, 4, 3, 6, 9, 12, 2, 2, 2, 2, 1, 1, 1, 1]);
+            na::DMatrix::from_row_slice(3, 3, &[6.0, 1.0, 2.0, 2.0, 3.0, 1.0, 1.0, 1.0, 1.0]);
+        let expected = FlowMap { flow: expected };
+
+        assert_eq!(FlowMap::from(&mesh, &downhill_map), expected);
+        assert_eq!(FlowMap::from_topological(&mesh, &downhill_map), expected);
+    }
+
+    #[test]
+    pub fn test_from_precipitation() {
+        let mesh = Mesh::new(4, 0.0);
+
+        let directions = vec![
+            vec![3, 3, 3, 3],
+            vec![3, 3, 3, 3],
+            vec![0, 0, 0, 0],
+            vec![0, 0, 0, 0],
+        ];
+        let downhill_map = MockDownhillMap::new(directions);
+        let downhill_map: Box<SingleDownhillMap> = Box::new(downhill_map);
+
+        let precipitation = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.5, 0.5, 0.5, 0.5, 2.0, 2.0, 2.0, 2.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
+            ],
+        );
+
+        let flow_map = FlowMap::from_precipitation(&mesh, &downhill_map, &precipitation);
+
+        let expected = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.5, 1.0, 1.5, 2.0, 3.0, 5.0, 7.0, 9.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
+            ],
+        );
         let expected = FlowMap { flow: expected };
 
         assert_eq!(flow_map, expected);
+
+        let mut rain_on_all = FlowMap::new(4);
+        rain_on_all.rain_on_all(&mesh, &downhill_map, &precipitation);
+        assert_eq!(rain_on_all, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Precipitation must be the same size as the mesh")]
+    pub fn from_precipitation_should_reject_wrong_size() {
+        let mesh = Mesh::new(4, 0.0);
+        let downhill_map: Box<SingleDownhillMap> =
+            Box::new(MockDownhillMap::new(vec![vec![0; 4]; 4]));
+
+        FlowMap::from_precipitation(&mesh, &downhill_map, &na::DMatrix::repeat(3, 3, 1.0));
     }
 
     #[test]
@@ -110,10 +368,13 @@ mod tests {
         let flow = na::DMatrix::from_row_slice(
             4,
             4,
-            &[1, 9, 4, 10, 14, 12, 5, 11, 7, 2, 16, 8, 13, 3, 15, 6],
+            &[
+                1.0, 9.0, 4.0, 10.0, 14.0, 12.0, 5.0, 11.0, 7.0, 2.0, 16.0, 8.0, 13.0, 3.0, 15.0,
+                6.0,
+            ],
         );
         let flow_map = FlowMap { flow };
-        assert_eq!(flow_map.get_max_flow(), 16);
+        assert_eq!(flow_map.get_max_flow(), 16.0);
     }
 
 }
@@ -0,0 +1,329 @@
+use mesh::Mesh;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum GridError {
+    Io(io::Error),
+    InvalidHeader(String),
+    InvalidValue(String),
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for GridError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GridError::Io(error) => write!(f, "{}", error),
+            GridError::InvalidHeader(message) => write!(f, "Invalid header: {}", message),
+            GridError::InvalidValue(value) => write!(f, "Invalid value: {}", value),
+            GridError::SizeMismatch { expected, actual } => {
+                write!(f, "Expected {} values but found {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for GridError {
+    fn from(error: io::Error) -> GridError {
+        GridError::Io(error)
+    }
+}
+
+// Meshes index cells with i32, so larger grids could not be read even without overflow
+fn get_cell_count(width: i32, height: i32) -> Result<usize, GridError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .filter(|count| *count <= i32::MAX as usize)
+        .ok_or_else(|| GridError::InvalidHeader(format!("Size {}x{} is too large", width, height)))
+}
+
+/// Reads and writes ESRI ASCII Grids. The first row of the grid is the most northerly, which
+/// becomes y = 0.
+pub struct AsciiGrid {}
+
+impl AsciiGrid {
+    /// Cells equal to the grid's `NODATA_value` are set to `out_of_bounds_z`, as if they were
+    /// off the mesh.
+    pub fn read<R: BufRead>(reader: R, out_of_bounds_z: f64) -> Result<Mesh, GridError> {
+        let mut width = None;
+        let mut height = None;
+        let mut nodata = None;
+        let mut values = vec![];
+
+        for line in reader.lines() {
+            let line = line?;
+            let mut tokens = line.split_whitespace().peekable();
+            let is_header = match tokens.peek() {
+                Some(token) => values.is_empty() && token.starts_with(char::is_alphabetic),
+                None => continue,
+            };
+            if is_header {
+                let key = tokens.next().unwrap().to_lowercase();
+                let value = match (tokens.next(), tokens.next()) {
+                    (Some(value), None) => value,
+                    _ => return Err(GridError::InvalidHeader(line.clone())),
+                };
+                let parse_size = || match value.parse::<i32>() {
+                    Ok(size) if size > 0 => Ok(Some(size)),
+                    _ => Err(GridError::InvalidHeader(line.clone())),
+                };
+                let parse_number = || {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| GridError::InvalidHeader(line.clone()))
+                };
+                match key.as_str() {
+                    "ncols" => width = parse_size()?,
+                    "nrows" => height = parse_size()?,
+                    "nodata_value" => nodata = Some(parse_number()?),
+                    "xllcorner" | "yllcorner" | "xllcenter" | "yllcenter" | "cellsize" => {
+                        parse_number()?;
+                    }
+                    _ => return Err(GridError::InvalidHeader(line.clone())),
+                }
+            } else {
+                for token in tokens {
+                    let value = token
+                        .parse::<f64>()
+                        .map_err(|_| GridError::InvalidValue(token.to_string()))?;
+                    values.push(value);
+                }
+            }
+        }
+
+        let width = width.ok_or_else(|| GridError::InvalidHeader("Missing ncols".to_string()))?;
+        let height = height.ok_or_else(|| GridError::InvalidHeader("Missing nrows".to_string()))?;
+        let expected = get_cell_count(width, height)?;
+        if values.len() != expected {
+            return Err(GridError::SizeMismatch {
+                expected,
+                actual: values.len(),
+            });
+        }
+
+        let mut out = Mesh::with_size(width, height, out_of_bounds_z);
+        for x in 0..width {
+            for y in 0..height {
+                let value = values[(y * width + x) as usize];
+                if Some(value) == nodata {
+                    out.set_z(x, y, out_of_bounds_z);
+                } else {
+                    out.set_z(x, y, value);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Writes the grid with its lower left corner at the origin and cells one unit apart.
+    pub fn write<W: Write>(mesh: &Mesh, mut writer: W) -> Result<(), GridError> {
+        writeln!(writer, "ncols {}", mesh.get_width())?;
+        writeln!(writer, "nrows {}", mesh.get_height())?;
+        writeln!(writer, "xllcorner 0")?;
+        writeln!(writer, "yllcorner 0")?;
+        writeln!(writer, "cellsize 1")?;
+        for y in 0..mesh.get_height() {
+            let row: Vec<String> = (0..mesh.get_width())
+                .map(|x| mesh.get_z(x, y).to_string())
+                .collect();
+            writeln!(writer, "{}", row.join(" "))?;
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, out_of_bounds_z: f64) -> Result<Mesh, GridError> {
+        AsciiGrid::read(BufReader::new(File::open(path)?), out_of_bounds_z)
+    }
+
+    pub fn save<P: AsRef<Path>>(mesh: &Mesh, path: P) -> Result<(), GridError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        AsciiGrid::write(mesh, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads and writes headerless grids of little-endian 32-bit floats, one row of x after
+/// another.
+pub struct RawGrid {}
+
+impl RawGrid {
+    pub fn read<R: Read>(
+        mut reader: R,
+        width: i32,
+        height: i32,
+        out_of_bounds_z: f64,
+    ) -> Result<Mesh, GridError> {
+        if width <= 0 || height <= 0 {
+            return Err(GridError::InvalidHeader(format!(
+                "Size must be positive but was {}x{}",
+                width, height
+            )));
+        }
+        let expected = get_cell_count(width, height)?;
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() % 4 != 0 || bytes.len() / 4 != expected {
+            return Err(GridError::SizeMismatch {
+                expected,
+                actual: bytes.len() / 4,
+            });
+        }
+
+        let mut out = Mesh::with_size(width, height, out_of_bounds_z);
+        for (i, value) in bytes.chunks(4).enumerate() {
+            let value =
+                f32::from_bits(u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+            let (x, y) = (i as i32 % width, i as i32 / width);
+            out.set_z(x, y, value as f64);
+        }
+        Ok(out)
+    }
+
+    pub fn write<W: Write>(mesh: &Mesh, mut writer: W) -> Result<(), GridError> {
+        for y in 0..mesh.get_height() {
+            for x in 0..mesh.get_width() {
+                let value = mesh.get_z(x, y) as f32;
+                writer.write_all(&value.to_bits().to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        width: i32,
+        height: i32,
+        out_of_bounds_z: f64,
+    ) -> Result<Mesh, GridError> {
+        RawGrid::read(File::open(path)?, width, height, out_of_bounds_z)
+    }
+
+    pub fn save<P: AsRef<Path>>(mesh: &Mesh, path: P) -> Result<(), GridError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        RawGrid::write(mesh, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn mesh() -> Mesh {
+        let mut mesh = Mesh::with_size(3, 2, -1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[0.5, 1.5, 2.25, -3.0, 4.0, 100.125],
+        ));
+        mesh
+    }
+
+    #[test]
+    fn test_read_ascii() {
+        let asc = "NCOLS 3\nNROWS 2\nXLLCENTER 10.5\nYLLCENTER -4\nCELLSIZE 30\n\
+                   NODATA_VALUE -9999\n0.5 2.25\n4 -9999 -3.0 100.125\n";
+
+        let actual = AsciiGrid::read(asc.as_bytes(), -1.0).unwrap();
+
+        let mut expected = mesh();
+        expected.set_z(0, 1, -1.0);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ascii_should_round_trip() {
+        let mut asc = vec![];
+        AsciiGrid::write(&mesh(), &mut asc).unwrap();
+
+        let text = String::from_utf8(asc.clone()).unwrap();
+        assert_eq!(text.lines().nth(5), Some("0.5 2.25 4"));
+        assert_eq!(AsciiGrid::read(&asc[..], -1.0).unwrap(), mesh());
+    }
+
+    #[test]
+    fn read_ascii_should_reject_size_mismatch() {
+        let asc = "ncols 2\nnrows 2\ncellsize 1\n1 2 3\n";
+
+        match AsciiGrid::read(asc.as_bytes(), 0.0) {
+            Err(GridError::SizeMismatch {
+                expected: 4,
+                actual: 3,
+            }) => (),
+            other => panic!("Expected SizeMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_ascii_should_reject_malformed_headers() {
+        let cases = [
+            "ncols 2\n1 2\n",
+            "ncols two\nnrows 1\n1 2\n",
+            "ncols 0\nnrows 1\n",
+            "ncols 2 3\nnrows 1\n1 2\n",
+            "ncols 2\nnrows 1\ncolour blue\n1 2\n",
+            "ncols 2147483647\nnrows 2147483647\n1 2\n",
+        ];
+        for asc in cases.iter() {
+            match AsciiGrid::read(asc.as_bytes(), 0.0) {
+                Err(GridError::InvalidHeader(_)) => (),
+                other => panic!("Expected InvalidHeader for {:?}, got {:?}", asc, other),
+            }
+        }
+    }
+
+    #[test]
+    fn read_ascii_should_reject_malformed_values() {
+        let asc = "ncols 2\nnrows 1\n1 x\n";
+
+        match AsciiGrid::read(asc.as_bytes(), 0.0) {
+            Err(GridError::InvalidValue(value)) => assert_eq!(value, "x"),
+            other => panic!("Expected InvalidValue, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn raw_should_round_trip() {
+        let mut raw = vec![];
+        RawGrid::write(&mesh(), &mut raw).unwrap();
+
+        assert_eq!(raw.len(), 24);
+        assert_eq!(&raw[4..8], &2.25f32.to_le_bytes());
+        assert_eq!(RawGrid::read(&raw[..], 3, 2, -1.0).unwrap(), mesh());
+    }
+
+    #[test]
+    fn read_raw_should_reject_empty_size() {
+        match RawGrid::read(&[][..], 0, 2, 0.0) {
+            Err(GridError::InvalidHeader(_)) => (),
+            other => panic!("Expected InvalidHeader, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_raw_should_reject_oversized_grid() {
+        match RawGrid::read(&[][..], i32::MAX, i32::MAX, 0.0) {
+            Err(GridError::InvalidHeader(_)) => (),
+            other => panic!("Expected InvalidHeader, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_raw_should_reject_size_mismatch() {
+        let raw = vec![0; 20];
+
+        match RawGrid::read(&raw[..], 3, 2, 0.0) {
+            Err(GridError::SizeMismatch {
+                expected: 6,
+                actual: 5,
+            }) => (),
+            other => panic!("Expected SizeMismatch, got {:?}", other),
+        }
+    }
+
+}
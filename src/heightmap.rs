@@ -0,0 +1,208 @@
+use mesh::Mesh;
+use png::{
+    BitDepth, ColorType, Decoder, DecodingError, Encoder, EncodingError, HasParameters,
+    Transformations,
+};
+use scale::Scale;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+const MAX_VALUE: f64 = 65535.0;
+
+/// Reads and writes meshes as 16-bit grayscale PNGs. Each pixel is one cell, with x across and
+/// y down the image.
+pub struct Heightmap {}
+
+impl Heightmap {
+    /// `scale` maps z to 0..65535. Values outside that range are clamped.
+    pub fn write<W: Write>(mesh: &Mesh, scale: &Scale, writer: W) -> Result<(), EncodingError> {
+        let (width, height) = (mesh.get_width(), mesh.get_height());
+        let mut data = Vec::with_capacity(mesh.get_cell_count() * 2);
+        for y in 0..height {
+            for x in 0..width {
+                let value = scale
+                    .scale(mesh.get_z(x, y))
+                    .round()
+                    .max(0.0)
+                    .min(MAX_VALUE) as u16;
+                data.push((value >> 8) as u8);
+                data.push(value as u8);
+            }
+        }
+
+        let mut encoder = Encoder::new(writer, width as u32, height as u32);
+        encoder.set(ColorType::Grayscale).set(BitDepth::Sixteen);
+        encoder.write_header()?.write_image_data(&data)
+    }
+
+    pub fn save<P: AsRef<Path>>(mesh: &Mesh, scale: &Scale, path: P) -> Result<(), EncodingError> {
+        Heightmap::write(mesh, scale, BufWriter::new(File::create(path)?))
+    }
+
+    /// `scale` maps 0..65535 to z. 8-bit grayscale images are also accepted, with each value
+    /// treated as if it had been widened to 16 bits.
+    pub fn read<R: Read>(
+        reader: R,
+        scale: &Scale,
+        out_of_bounds_z: f64,
+    ) -> Result<Mesh, DecodingError> {
+        let mut decoder = Decoder::new(reader);
+        decoder.set(Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info()?;
+        if info.color_type != ColorType::Grayscale {
+            return Err(DecodingError::Format(
+                "Heightmap must be a grayscale image".into(),
+            ));
+        }
+        if info.bit_depth != BitDepth::Eight && info.bit_depth != BitDepth::Sixteen {
+            return Err(DecodingError::Format(
+                "Heightmap must have a bit depth of 8 or 16".into(),
+            ));
+        }
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data)?;
+
+        let (width, height) = (info.width as i32, info.height as i32);
+        let get_value = |x: i32, y: i32| {
+            let row = &data[y as usize * info.line_size..];
+            if info.bit_depth == BitDepth::Sixteen {
+                let i = x as usize * 2;
+                (((row[i] as u16) << 8) | row[i + 1] as u16) as f64
+            } else {
+                row[x as usize] as f64 * 257.0
+            }
+        };
+
+        let mut out = Mesh::with_size(width, height, out_of_bounds_z);
+        for x in 0..width {
+            for y in 0..height {
+                out.set_z(x, y, scale.scale(get_value(x, y)));
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        scale: &Scale,
+        out_of_bounds_z: f64,
+    ) -> Result<Mesh, DecodingError> {
+        Heightmap::read(File::open(path)?, scale, out_of_bounds_z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn mesh() -> Mesh {
+        let mut mesh = Mesh::with_size(3, 2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[0.0, 0.25, 0.5, 0.75, 1.0, 0.125],
+        ));
+        mesh
+    }
+
+    fn to_png() -> Scale {
+        Scale::new((0.0, 1.0), (0.0, MAX_VALUE))
+    }
+
+    fn from_png() -> Scale {
+        Scale::new((0.0, MAX_VALUE), (0.0, 1.0))
+    }
+
+    fn encode(width: u32, height: u32, color_type: ColorType, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        {
+            let mut encoder = Encoder::new(&mut out, width, height);
+            encoder.set(color_type).set(BitDepth::Eight);
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(data)
+                .unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_write() {
+        let mut png = vec![];
+        Heightmap::write(&mesh(), &to_png(), &mut png).unwrap();
+
+        let mut decoder = Decoder::new(&png[..]);
+        decoder.set(Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.bit_depth, BitDepth::Sixteen);
+        assert_eq!(data, vec![0, 0, 128, 0, 255, 255, 64, 0, 191, 255, 32, 0]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut png = vec![];
+        Heightmap::write(&mesh(), &to_png(), &mut png).unwrap();
+
+        let actual = Heightmap::read(&png[..], &from_png(), 0.0).unwrap();
+
+        assert_eq!(actual.get_shape(), (3, 2));
+        for x in 0..3 {
+            for y in 0..2 {
+                assert!((actual.get_z(x, y) - mesh().get_z(x, y)).abs() <= 0.5 / MAX_VALUE);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_should_be_exact_for_whole_values() {
+        let mut mesh = Mesh::with_size(2, 2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            2,
+            2,
+            &[0.0, 1.0, 65534.0, 65535.0],
+        ));
+        let identity = Scale::new((0.0, MAX_VALUE), (0.0, MAX_VALUE));
+        let mut png = vec![];
+        Heightmap::write(&mesh, &identity, &mut png).unwrap();
+
+        assert_eq!(Heightmap::read(&png[..], &identity, 0.0).unwrap(), mesh);
+    }
+
+    #[test]
+    fn write_should_clamp() {
+        let mut mesh = Mesh::with_size(2, 1, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 1, &[-0.5, 1.5]));
+        let mut png = vec![];
+        Heightmap::write(&mesh, &to_png(), &mut png).unwrap();
+
+        let actual = Heightmap::read(&png[..], &from_png(), 0.0).unwrap();
+
+        assert_eq!(actual.get_z(0, 0), 0.0);
+        assert_eq!(actual.get_z(1, 0), 1.0);
+    }
+
+    #[test]
+    fn test_read_eight_bit() {
+        let png = encode(2, 1, ColorType::Grayscale, &[0, 255]);
+
+        let actual = Heightmap::read(&png[..], &from_png(), 0.0).unwrap();
+
+        assert_eq!(actual.get_z(0, 0), 0.0);
+        assert_eq!(actual.get_z(1, 0), 1.0);
+    }
+
+    #[test]
+    fn read_should_reject_colour() {
+        let png = encode(1, 1, ColorType::RGB, &[0, 0, 0]);
+
+        assert!(Heightmap::read(&png[..], &from_png(), 0.0).is_err());
+    }
+
+}
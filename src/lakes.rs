@@ -0,0 +1,292 @@
+use depression_filler::DepressionFiller;
+use downhill_map::{DownhillMap, ALL_DIRECTIONS, DIRECTIONS};
+use mesh::Mesh;
+use rand::prelude::*;
+use single_downhill_map::SingleDownhillMap;
+use std::collections::VecDeque;
+
+#[derive(Debug, PartialEq)]
+pub struct Lake {
+    id: usize,
+    spill_z: f64,
+    outlet: Option<(i32, i32)>,
+    size: usize,
+}
+
+impl Lake {
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn get_spill_z(&self) -> f64 {
+        self.spill_z
+    }
+
+    /// The cell outside the lake that the lake overflows into, or None if it overflows off the
+    /// edge of the mesh.
+    pub fn get_outlet(&self) -> Option<(i32, i32)> {
+        self.outlet
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LakeMap {
+    width: i32,
+    height: i32,
+    lakes: Vec<Lake>,
+    lake_ids: na::DMatrix<Option<usize>>,
+    water: Mesh,
+    outflow_directions: na::DMatrix<u8>,
+}
+
+impl LakeMap {
+    pub fn new(mesh: &Mesh) -> LakeMap {
+        let (width, height) = (mesh.get_width(), mesh.get_height());
+        let (water, outflow_directions, order) = DepressionFiller::flood(mesh, 0.0);
+        let mut out = LakeMap {
+            width,
+            height,
+            lakes: vec![],
+            lake_ids: na::DMatrix::repeat(width as usize, height as usize, None),
+            water,
+            outflow_directions,
+        };
+        out.label_lakes(mesh, &order);
+        out
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn get_lakes(&self) -> &Vec<Lake> {
+        &self.lakes
+    }
+
+    pub fn get_lake(&self, id: usize) -> &Lake {
+        &self.lakes[id]
+    }
+
+    pub fn get_lake_id(&self, x: i32, y: i32) -> Option<usize> {
+        self.lake_ids[(x as usize, y as usize)]
+    }
+
+    pub fn is_lake(&self, x: i32, y: i32) -> bool {
+        self.get_lake_id(x, y).is_some()
+    }
+
+    /// Water surface inside lakes, terrain height elsewhere.
+    pub fn get_water_z(&self, x: i32, y: i32) -> f64 {
+        self.water.get_z(x, y)
+    }
+
+    fn is_submerged(&self, mesh: &Mesh, x: i32, y: i32) -> bool {
+        self.water.get_z(x, y) > mesh.get_z(x, y)
+    }
+
+    fn label_lakes(&mut self, mesh: &Mesh, order: &[(i32, i32)]) {
+        for (x, y) in order.iter() {
+            if self.is_submerged(mesh, *x, *y) && !self.is_lake(*x, *y) {
+                self.label_lake(mesh, *x, *y);
+            }
+        }
+    }
+
+    // (x, y) must be the first cell of the lake to be flooded. Its flood direction leads to the
+    // outlet, and every other cell in the lake is pointed back towards it.
+    fn label_lake(&mut self, mesh: &Mesh, x: i32, y: i32) {
+        let id = self.lakes.len();
+        let mut size = 0;
+        let mut queue = VecDeque::new();
+        self.lake_ids[(x as usize, y as usize)] = Some(id);
+        queue.push_back((x, y));
+        while let Some((x, y)) = queue.pop_front() {
+            size += 1;
+            for direction in DIRECTIONS.iter() {
+                let (nx, ny) = match mesh.get_neighbour(x, y, *direction) {
+                    Some(neighbour) => neighbour,
+                    None => continue,
+                };
+                if self.is_lake(nx, ny) || !self.is_submerged(mesh, nx, ny) {
+                    continue;
+                }
+                let back = DIRECTIONS
+                    .iter()
+                    .position(|d| mesh.get_neighbour(nx, ny, *d) == Some((x, y)));
+                if let Some(back) = back {
+                    self.lake_ids[(nx as usize, ny as usize)] = Some(id);
+                    self.outflow_directions[(nx as usize, ny as usize)] = back as u8;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+
+        let direction = DIRECTIONS[self.outflow_directions[(x as usize, y as usize)] as usize];
+        let outlet = mesh.get_neighbour(x, y, direction);
+
+        self.lakes.push(Lake {
+            id,
+            spill_z: self.water.get_z(x, y),
+            outlet,
+            size,
+        });
+    }
+}
+
+/// Routes water through lakes to their outlets. Outside lakes, a random direction is chosen
+/// among downhill neighbours with a lower water surface. Cells with no such neighbour (lakes and
+/// flats) follow the path their water level was flooded along, which for lakes leads to the
+/// outlet.
+pub struct LakeDownhillMap {
+    width: i32,
+    height: i32,
+    directions: na::DMatrix<u8>,
+}
+
+impl LakeDownhillMap {
+    pub fn new<R: Rng>(
+        downhill_map: &DownhillMap,
+        lake_map: &LakeMap,
+        rng: &mut Box<R>,
+    ) -> LakeDownhillMap {
+        let (width, height) = (downhill_map.get_width(), downhill_map.get_height());
+        let mut directions = na::DMatrix::zeros(width as usize, height as usize);
+        for x in 0..width {
+            for y in 0..height {
+                let water_z = lake_map.get_water_z(x, y);
+                let candidates: Vec<u8> = downhill_map
+                    .get_directions(x, y)
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, downhill)| {
+                        let (dx, dy) = ALL_DIRECTIONS[*index];
+                        **downhill && lake_map.get_water_z(x + dx, y + dy) < water_z
+                    })
+                    .map(|(index, _)| index as u8)
+                    .collect();
+
+                directions[(x as usize, y as usize)] = match candidates.choose(&mut *rng) {
+                    Some(direction) => *direction,
+                    None => lake_map.outflow_directions[(x as usize, y as usize)],
+                };
+            }
+        }
+        LakeDownhillMap {
+            width,
+            height,
+            directions,
+        }
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+}
+
+impl SingleDownhillMap for LakeDownhillMap {
+    fn get_direction(&self, x: i32, y: i32) -> usize {
+        self.directions[(x as usize, y as usize)] as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use flow_map::FlowMap;
+    use rand::rngs::StdRng;
+
+    fn mesh() -> Mesh {
+        let mut mesh = Mesh::new(5, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            5,
+            5,
+            &[
+                0.9, 0.9, 0.9, 0.9, 0.9, 0.9, 0.2, 0.3, 0.9, 0.9, 0.9, 0.1, 0.9, 0.5, 0.4, 0.9,
+                0.9, 0.9, 0.9, 0.9, 0.9, 0.9, 0.9, 0.9, 0.9,
+            ],
+        ));
+        mesh
+    }
+
+    #[test]
+    fn test_lake_ids() {
+        let lake_map = LakeMap::new(&mesh());
+
+        assert_eq!(lake_map.get_lakes().len(), 1);
+        for x in 0..5 {
+            for y in 0..5 {
+                let expected = if (x, y) == (1, 1) || (x, y) == (1, 2) || (x, y) == (2, 1) {
+                    Some(0)
+                } else {
+                    None
+                };
+                assert_eq!(lake_map.get_lake_id(x, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lake() {
+        let lake_map = LakeMap::new(&mesh());
+
+        let expected = Lake {
+            id: 0,
+            spill_z: 0.9,
+            outlet: Some((0, 1)),
+            size: 3,
+        };
+
+        assert_eq!(lake_map.get_lake(0), &expected);
+    }
+
+    #[test]
+    fn test_water_z() {
+        let lake_map = LakeMap::new(&mesh());
+
+        assert_eq!(lake_map.get_water_z(2, 1), 0.9);
+        assert_eq!(lake_map.get_water_z(2, 3), 0.5);
+        assert_eq!(lake_map.get_water_z(-1, 0), 0.0);
+    }
+
+    #[test]
+    fn test_outlet_off_edge() {
+        let mut mesh = Mesh::new(2, 0.5);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.1, 0.2, 0.6, 0.7]));
+
+        let lake_map = LakeMap::new(&mesh);
+
+        assert_eq!(lake_map.get_lakes().len(), 1);
+        assert_eq!(lake_map.get_lake(0).get_outlet(), None);
+        assert_eq!(lake_map.get_lake(0).get_spill_z(), 0.5);
+        assert_eq!(lake_map.get_lake(0).get_size(), 2);
+    }
+
+    #[test]
+    fn lake_downhill_map_should_route_lakes_to_outlet() {
+        let mesh = mesh();
+        let downhill_map = DownhillMap::new(&mesh);
+        let lake_map = LakeMap::new(&mesh);
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let lake_downhill_map: Box<SingleDownhillMap> =
+            Box::new(LakeDownhillMap::new(&downhill_map, &lake_map, &mut rng));
+
+        let flow_map = FlowMap::from_topological(&mesh, &lake_downhill_map);
+
+        assert_eq!(flow_map.get_flow(0, 1), 5.0);
+        assert_eq!(flow_map, FlowMap::from(&mesh, &lake_downhill_map));
+    }
+
+}
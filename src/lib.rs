@@ -1,15 +1,42 @@
+pub mod depression_filler;
 pub mod downhill_map;
+pub mod droplet_erosion;
+pub mod edge_policy;
 pub mod erosion;
 pub mod flow_map;
+pub mod grid;
+pub mod heightmap;
+pub mod lakes;
 pub mod mesh;
 pub mod mesh_splitter;
+pub mod multiple_flow_map;
+pub mod pipeline;
+pub mod precipitation;
+pub mod render;
 pub mod river_runner;
 pub mod scale;
 pub mod single_downhill_map;
+pub mod stream_power;
+pub mod surface;
+pub mod thermal_erosion;
 pub mod utils;
+#[cfg(feature = "serde")]
+pub mod world;
 
+#[cfg(feature = "serde")]
+extern crate bincode;
 extern crate isometric;
 pub extern crate nalgebra as na;
+extern crate png;
 pub extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "serde")]
+extern crate toml;
 
 pub use rand::prelude::*;
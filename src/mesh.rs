@@ -1,20 +1,36 @@
+use edge_policy::{Boundary, EdgePolicy, Location};
 use scale::Scale;
 use std::f64;
 use utils::float_ordering;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Mesh {
     width: i32,
+    height: i32,
     z: na::DMatrix<f64>,
-    out_of_bounds_z: f64,
+    edge_policy: EdgePolicy,
 }
 
 impl Mesh {
     pub fn new(width: i32, out_of_bounds_z: f64) -> Mesh {
+        Mesh::with_size(width, width, out_of_bounds_z)
+    }
+
+    pub fn with_size(width: i32, height: i32, out_of_bounds_z: f64) -> Mesh {
+        Mesh::with_edge_policy(
+            width,
+            height,
+            EdgePolicy::all(Boundary::Constant(out_of_bounds_z)),
+        )
+    }
+
+    pub fn with_edge_policy(width: i32, height: i32, edge_policy: EdgePolicy) -> Mesh {
         Mesh {
             width,
-            z: na::DMatrix::zeros(width as usize, width as usize),
-            out_of_bounds_z,
+            height,
+            z: na::DMatrix::zeros(width as usize, height as usize),
+            edge_policy,
         }
     }
 
@@ -22,6 +38,18 @@ impl Mesh {
         self.width
     }
 
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn get_cell_count(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+
+    pub fn get_shape(&self) -> (usize, usize) {
+        (self.width as usize, self.height as usize)
+    }
+
     pub fn get_z_in_bounds(&self, x: i32, y: i32) -> f64 {
         self.z[(x as usize, y as usize)]
     }
@@ -31,14 +59,45 @@ impl Mesh {
     }
 
     pub fn in_bounds(&self, x: i32, y: i32) -> bool {
-        x >= 0 && y >= 0 && x < self.width && y < self.width
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    pub fn get_edge_policy(&self) -> EdgePolicy {
+        self.edge_policy
+    }
+
+    pub fn set_edge_policy(&mut self, edge_policy: EdgePolicy) {
+        self.edge_policy = edge_policy;
+    }
+
+    /// The cell that (`x`, `y`) corresponds to under the edge policy, or None if it is off the
+    /// mesh.
+    pub fn resolve(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        if self.in_bounds(x, y) {
+            return Some((x, y));
+        }
+        match self.edge_policy.locate(x, y, self.width, self.height) {
+            Location::Cell(x, y) => Some((x, y)),
+            Location::Exit(_) => None,
+        }
+    }
+
+    /// The cell reached by moving from (`x`, `y`) in `direction`, or None if that leaves the
+    /// mesh or leads back to the same cell.
+    pub fn get_neighbour(&self, x: i32, y: i32, direction: (i32, i32)) -> Option<(i32, i32)> {
+        match self.resolve(x + direction.0, y + direction.1) {
+            Some(cell) if cell != (x, y) => Some(cell),
+            _ => None,
+        }
     }
 
     pub fn get_z(&self, x: i32, y: i32) -> f64 {
         if self.in_bounds(x, y) {
-            self.get_z_in_bounds(x, y)
-        } else {
-            self.out_of_bounds_z
+            return self.get_z_in_bounds(x, y);
+        }
+        match self.edge_policy.locate(x, y, self.width, self.height) {
+            Location::Cell(x, y) => self.get_z_in_bounds(x, y),
+            Location::Exit(z) => z,
         }
     }
 
@@ -46,7 +105,10 @@ impl Mesh {
         self.z[(x as usize, y as usize)] = z;
     }
 
+    /// The mesh takes the size of `z`.
     pub fn set_z_vector(&mut self, z: na::DMatrix<f64>) {
+        self.width = z.nrows() as i32;
+        self.height = z.ncols() as i32;
         self.z = z;
     }
 
@@ -58,14 +120,63 @@ impl Mesh {
         *self.z.iter().max_by(float_ordering).unwrap()
     }
 
+    /// Panics unless every edge is `Boundary::Constant` with the same height, as for meshes
+    /// from `Mesh::new` and `Mesh::with_size`. Use `get_edge_policy` for other meshes.
     pub fn get_out_of_bounds_z(&self) -> f64 {
-        self.out_of_bounds_z
+        let policy = self.edge_policy;
+        match policy.get_west() {
+            Boundary::Constant(z) if policy == EdgePolicy::all(Boundary::Constant(z)) => z,
+            _ => panic!("Edges do not have a single constant height"),
+        }
+    }
+
+    /// Cells of the new mesh that are outside this mesh take their height from the edge policy.
+    pub fn crop(&self, x: i32, y: i32, width: i32, height: i32) -> Mesh {
+        let mut out = Mesh::with_edge_policy(width, height, self.edge_policy);
+        for cx in 0..width {
+            for cy in 0..height {
+                out.set_z(cx, cy, self.get_z(x + cx, y + cy));
+            }
+        }
+        out
+    }
+
+    /// Bilinear interpolation onto a mesh of the given size, with the corner cells of both
+    /// meshes lined up.
+    pub fn resample(&self, width: i32, height: i32) -> Mesh {
+        fn get_source(target: i32, target_size: i32, source_size: i32) -> f64 {
+            if target_size > 1 {
+                target as f64 * (source_size - 1) as f64 / (target_size - 1) as f64
+            } else {
+                0.0
+            }
+        }
+
+        let mut out = Mesh::with_edge_policy(width, height, self.edge_policy);
+        for x in 0..width {
+            for y in 0..height {
+                let sx = get_source(x, width, self.width);
+                let sy = get_source(y, height, self.height);
+                let (x0, y0) = (
+                    (sx.floor() as i32).min(self.width - 1),
+                    (sy.floor() as i32).min(self.height - 1),
+                );
+                let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+                let (u, v) = (sx - x0 as f64, sy - y0 as f64);
+                let z = self.get_z(x0, y0) * (1.0 - u) * (1.0 - v)
+                    + self.get_z(x1, y0) * u * (1.0 - v)
+                    + self.get_z(x0, y1) * (1.0 - u) * v
+                    + self.get_z(x1, y1) * u * v;
+                out.set_z(x, y, z);
+            }
+        }
+        out
     }
 
     pub fn rescale(&self, scale: &Scale) -> Mesh {
-        let mut out = Mesh::new(self.width, self.out_of_bounds_z);
+        let mut out = Mesh::with_edge_policy(self.width, self.height, self.edge_policy);
         for x in 0..self.width {
-            for y in 0..self.width {
+            for y in 0..self.height {
                 out.set_z(x, y, scale.scale(self.get_z(x, y)));
             }
         }
@@ -116,4 +227,103 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_rectangular_mesh() {
+        let mut mesh = Mesh::with_size(3, 2, -1.0);
+        mesh.set_z(2, 1, 0.5);
+
+        assert_eq!(mesh.get_width(), 3);
+        assert_eq!(mesh.get_height(), 2);
+        assert_eq!(mesh.get_shape(), (3, 2));
+        assert_eq!(mesh.get_z(2, 1), 0.5);
+        assert_eq!(mesh.in_bounds(2, 1), true);
+        assert_eq!(mesh.in_bounds(1, 2), false);
+        assert_eq!(mesh.get_z(1, 2), -1.0);
+    }
+
+    #[test]
+    fn test_get_z_with_edge_policy() {
+        let mut mesh = Mesh::with_edge_policy(
+            3,
+            2,
+            EdgePolicy::new(
+                Boundary::Wrap,
+                Boundary::Constant(-1.0),
+                Boundary::Wrap,
+                Boundary::Clamp,
+            ),
+        );
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        ));
+
+        assert_eq!(mesh.get_z(-1, 0), 0.5);
+        assert_eq!(mesh.get_z(3, 1), 0.2);
+        assert_eq!(mesh.get_z(1, -1), -1.0);
+        assert_eq!(mesh.get_z(1, 2), 0.4);
+        assert_eq!(mesh.resolve(-1, 0), Some((2, 0)));
+        assert_eq!(mesh.resolve(1, -1), None);
+    }
+
+    #[test]
+    fn test_get_out_of_bounds_z() {
+        assert_eq!(Mesh::with_size(3, 2, -1.0).get_out_of_bounds_z(), -1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Edges do not have a single constant height")]
+    fn get_out_of_bounds_z_should_reject_other_edges() {
+        Mesh::with_edge_policy(3, 2, EdgePolicy::all(Boundary::Clamp)).get_out_of_bounds_z();
+    }
+
+    #[test]
+    fn test_get_neighbour() {
+        let mesh = Mesh::with_edge_policy(3, 2, EdgePolicy::all(Boundary::Clamp));
+
+        assert_eq!(mesh.get_neighbour(0, 0, (1, 0)), Some((1, 0)));
+        assert_eq!(mesh.get_neighbour(0, 1, (-1, -1)), Some((0, 0)));
+        assert_eq!(mesh.get_neighbour(0, 0, (-1, 0)), None);
+        assert_eq!(Mesh::new(2, 0.0).get_neighbour(0, 0, (0, -1)), None);
+    }
+
+    #[test]
+    fn test_crop() {
+        let mut mesh = Mesh::with_size(3, 2, -1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        ));
+
+        let actual = mesh.crop(1, 1, 3, 1);
+
+        let mut expected = Mesh::with_size(3, 1, -1.0);
+        expected.set_z_vector(na::DMatrix::from_row_slice(3, 1, &[0.4, 0.6, -1.0]));
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_resample() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.0, 1.0, 2.0, 3.0]));
+
+        let actual = mesh.resample(3, 2);
+
+        let expected = na::DMatrix::from_row_slice(3, 2, &[0.0, 1.0, 1.0, 2.0, 2.0, 3.0]);
+        assert_eq!(actual.get_z_vector(), &expected);
+        assert_eq!(mesh.resample(2, 2), mesh);
+    }
+
+    #[test]
+    fn set_z_vector_should_resize_mesh() {
+        let mut mesh = Mesh::new(2, 0.0);
+
+        mesh.set_z_vector(na::DMatrix::zeros(4, 3));
+
+        assert_eq!(mesh.get_width(), 4);
+        assert_eq!(mesh.get_height(), 3);
+    }
+
 }
@@ -1,7 +1,9 @@
+use edge_policy::Location;
 use mesh::Mesh;
 use utils::float_ordering;
 
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use scale::Scale;
 
 #[derive(Debug, PartialEq)]
@@ -26,7 +28,11 @@ struct SplitProcess {
 
 impl SplitRule {
     fn generate_split<R: Rng>(&self, rng: &mut Box<R>, random_range: (f64, f64)) -> Split {
-        let r: f64 = rng.gen_range(random_range.0, random_range.1);
+        let r: f64 = if random_range.0 < random_range.1 {
+            rng.gen_range(random_range.0, random_range.1)
+        } else {
+            random_range.0
+        };
         let scale: Scale = Scale::new((0.0, 1.0), self.range);
         Split {
             x: self.x,
@@ -38,6 +44,13 @@ impl SplitRule {
 
 impl SplitProcess {
     fn new(mesh: &Mesh, x: i32, y: i32) -> SplitProcess {
+        SplitProcess::with_z(x, y, |x, y| mesh.get_z(x, y))
+    }
+
+    fn with_z<F>(x: i32, y: i32, get_z: F) -> SplitProcess
+    where
+        F: Fn(i32, i32) -> f64,
+    {
         const OFFSETS: [(i32, i32); 4] = [(0, 0), (0, 1), (1, 0), (1, 1)];
 
         let mut split_rules: Vec<SplitRule> = OFFSETS
@@ -45,8 +58,8 @@ impl SplitProcess {
             .map(|o| {
                 let dx: i32 = (o.0 as i32 * 2) - 1;
                 let dy: i32 = (o.1 as i32 * 2) - 1;
-                let z = mesh.get_z(x, y);
-                let zs = [mesh.get_z(x + dx, y), mesh.get_z(x, y + dy), z];
+                let z = get_z(x, y);
+                let zs = [get_z(x + dx, y), get_z(x, y + dy), z];
                 let min_z = zs.iter().min_by(float_ordering).unwrap();
 
                 SplitRule {
@@ -97,31 +110,179 @@ impl SplitProcess {
     }
 }
 
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn get_cell_rng(seed: u64, level: u32, x: i32, y: i32) -> Box<StdRng> {
+    let hash =
+        mix(mix(mix(mix(seed) ^ u64::from(level)) ^ u64::from(x as u32)) ^ u64::from(y as u32));
+    Box::new(StdRng::seed_from_u64(hash))
+}
+
+// Rounds down, unlike integer division, for cells left of or above the mesh
+fn half(value: i32) -> i32 {
+    value >> 1
+}
+
+/// A block of cells with its top left cell at (`x`, `y`), for use with
+/// `MeshSplitter::split_n_times_region`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Region {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn get_x(&self) -> i32 {
+        self.x
+    }
+
+    pub fn get_y(&self) -> i32 {
+        self.y
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    // The cells needed to split every cell in this region: their parents and the parents'
+    // neighbours
+    fn get_parent(&self) -> Region {
+        let x = half(self.x) - 1;
+        let y = half(self.y) - 1;
+        let width = half(self.x + self.width - 1) + 2 - x;
+        let height = half(self.y + self.height - 1) + 2 - y;
+        Region::new(x, y, width, height)
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+}
+
+/// How `MeshSplitter::split_to_size` gets from a split mesh to the size asked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    Crop,
+    Resample,
+}
+
 pub struct MeshSplitter {}
 
 impl MeshSplitter {
-    fn get_all_splits<R: Rng>(
-        mesh: &Mesh,
-        rng: &mut Box<R>,
-        random_range: (f64, f64),
-    ) -> Vec<Split> {
-        let mut out = Vec::with_capacity((mesh.get_width() * mesh.get_width() * 4) as usize);
+    fn get_all_splits<R: Rng, F>(mesh: &Mesh, rng: &mut Box<R>, random_range: F) -> Vec<Split>
+    where
+        F: Fn(i32, i32) -> (f64, f64),
+    {
+        let mut out = Vec::with_capacity(mesh.get_cell_count() * 4);
         for x in 0..mesh.get_width() {
-            for y in 0..mesh.get_width() {
-                out.append(&mut SplitProcess::new(mesh, x, y).complete(rng, random_range));
+            for y in 0..mesh.get_height() {
+                out.append(&mut SplitProcess::new(mesh, x, y).complete(rng, random_range(x, y)));
             }
         }
         out
     }
 
-    pub fn split<R: Rng>(mesh: &Mesh, rng: &mut Box<R>, random_range: (f64, f64)) -> Mesh {
-        let mut out = Mesh::new(mesh.get_width() * 2, mesh.get_out_of_bounds_z());
+    fn split_with<R: Rng, F>(mesh: &Mesh, rng: &mut Box<R>, random_range: F) -> Mesh
+    where
+        F: Fn(i32, i32) -> (f64, f64),
+    {
+        let mut out = Mesh::with_edge_policy(
+            mesh.get_width() * 2,
+            mesh.get_height() * 2,
+            mesh.get_edge_policy(),
+        );
         for split in MeshSplitter::get_all_splits(mesh, rng, random_range) {
             out.set_z(split.x, split.y, split.z);
         }
         out
     }
 
+    pub fn split<R: Rng>(mesh: &Mesh, rng: &mut Box<R>, random_range: (f64, f64)) -> Mesh {
+        MeshSplitter::split_with(mesh, rng, |_, _| random_range)
+    }
+
+    // Shrinks the range towards its lower end where the mask is below 1, so a mask of 0 gives
+    // no randomness at all. The mask covers the whole mesh whatever its size.
+    fn get_masked_range(
+        random_range: (f64, f64),
+        mask: &na::DMatrix<f64>,
+        mesh: &Mesh,
+        x: i32,
+        y: i32,
+    ) -> (f64, f64) {
+        let mx = (x as usize * mask.nrows()) / mesh.get_width() as usize;
+        let my = (y as usize * mask.ncols()) / mesh.get_height() as usize;
+        let roughness = mask[(mx, my)].max(0.0).min(1.0);
+        (
+            random_range.0,
+            random_range.0 + (random_range.1 - random_range.0) * roughness,
+        )
+    }
+
+    /// Like `split_n_times`, but split `n` (counting from 0) uses the range `schedule(n)`.
+    /// If there is a `mask`, each cell's range is narrowed by the mask value (from 0 to 1) at
+    /// the corresponding point, so low values give smooth terrain and high values rugged.
+    /// Where the mask is 0 every split takes the bottom of the range, which must be above 0
+    /// for the downhill property to be kept.
+    pub fn split_n_times_scheduled<R: Rng, F>(
+        mesh: &Mesh,
+        rng: &mut Box<R>,
+        schedule: F,
+        times: u32,
+        mask: Option<&na::DMatrix<f64>>,
+    ) -> Mesh
+    where
+        F: Fn(u32) -> (f64, f64),
+    {
+        let mut out = mesh.clone();
+        for level in 0..times {
+            let random_range = schedule(level);
+            out = match mask {
+                Some(mask) => MeshSplitter::split_with(&out, rng, |x, y| {
+                    MeshSplitter::get_masked_range(random_range, mask, &out, x, y)
+                }),
+                None => MeshSplitter::split(&out, rng, random_range),
+            };
+        }
+        out
+    }
+
+    /// Splits once for each range in `random_ranges`.
+    pub fn split_n_times_with_ranges<R: Rng>(
+        mesh: &Mesh,
+        rng: &mut Box<R>,
+        random_ranges: &[(f64, f64)],
+        mask: Option<&na::DMatrix<f64>>,
+    ) -> Mesh {
+        MeshSplitter::split_n_times_scheduled(
+            mesh,
+            rng,
+            |level| random_ranges[level as usize],
+            random_ranges.len() as u32,
+            mask,
+        )
+    }
+
     pub fn split_n_times<R: Rng>(
         mesh: &Mesh,
         rng: &mut Box<R>,
@@ -134,6 +295,149 @@ impl MeshSplitter {
         }
         out
     }
+
+    /// Splits until the mesh is at least `width` by `height`, then cuts the middle out of it or
+    /// resamples it to exactly that size. Cropping keeps the downhill property as long as the
+    /// out of bounds z is below every cell. Resampling does not guarantee it.
+    pub fn split_to_size<R: Rng>(
+        mesh: &Mesh,
+        rng: &mut Box<R>,
+        random_range: (f64, f64),
+        width: i32,
+        height: i32,
+        fit: Fit,
+    ) -> Mesh {
+        assert!(
+            mesh.get_width() > 0 && mesh.get_height() > 0,
+            "Cannot split an empty mesh to size"
+        );
+        assert!(width > 0 && height > 0, "Target size must be positive");
+        let mut out = mesh.clone();
+        while out.get_width() < width || out.get_height() < height {
+            out = MeshSplitter::split(&out, rng, random_range);
+        }
+        match fit {
+            Fit::Crop => out.crop(
+                (out.get_width() - width) / 2,
+                (out.get_height() - height) / 2,
+                width,
+                height,
+            ),
+            Fit::Resample => out.resample(width, height),
+        }
+    }
+
+    // `parent` holds the cells of `parent_region` from the mesh covering `world`. Every cell
+    // takes its random values from a hash of `seed`, `level` and its position, so the result
+    // does not depend on which region is being split.
+    fn split_region_seeded(
+        parent: &Mesh,
+        parent_region: Region,
+        world: Region,
+        seed: u64,
+        level: u32,
+        random_range: (f64, f64),
+        region: Region,
+    ) -> Mesh {
+        let edge_policy = parent.get_edge_policy();
+        let get_z = |x: i32, y: i32| match edge_policy.locate(x, y, world.width, world.height) {
+            Location::Cell(x, y) => parent.get_z(x - parent_region.x, y - parent_region.y),
+            Location::Exit(z) => z,
+        };
+
+        let mut out = Mesh::with_edge_policy(region.width, region.height, edge_policy);
+        for x in 0..region.width {
+            for y in 0..region.height {
+                let (wx, wy) = (x + region.x, y + region.y);
+                if let Location::Exit(z) =
+                    edge_policy.locate(wx, wy, world.width * 2, world.height * 2)
+                {
+                    out.set_z(x, y, z);
+                }
+            }
+        }
+        for x in half(region.x)..=half(region.x + region.width - 1) {
+            for y in half(region.y)..=half(region.y + region.height - 1) {
+                if !world.contains(x, y) {
+                    continue;
+                }
+                let mut rng = get_cell_rng(seed, level, x, y);
+                for split in SplitProcess::with_z(x, y, &get_z).complete(&mut rng, random_range) {
+                    if region.contains(split.x, split.y) {
+                        out.set_z(split.x - region.x, split.y - region.y, split.z);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Like `split`, but the result depends only on `seed` and `level` rather than on the state
+    /// of a shared random number generator. `level` should be different for each split.
+    pub fn split_seeded(mesh: &Mesh, seed: u64, level: u32, random_range: (f64, f64)) -> Mesh {
+        let (width, height) = (mesh.get_width(), mesh.get_height());
+        let world = Region::new(0, 0, width, height);
+        MeshSplitter::split_region_seeded(
+            mesh,
+            world,
+            world,
+            seed,
+            level,
+            random_range,
+            Region::new(0, 0, width * 2, height * 2),
+        )
+    }
+
+    pub fn split_n_times_seeded(
+        mesh: &Mesh,
+        seed: u64,
+        random_range: (f64, f64),
+        times: u32,
+    ) -> Mesh {
+        let mut out = mesh.clone();
+        for level in 0..times {
+            out = MeshSplitter::split_seeded(&out, seed, level, random_range);
+        }
+        out
+    }
+
+    /// Returns `region` of the mesh that `split_n_times_seeded` would return, bit for bit,
+    /// while only ever holding a few cells more than `region` in memory. Cells of `region`
+    /// outside the mesh are set to the out of bounds z. Only meshes whose edges are all
+    /// `Boundary::Constant` are supported, since other edges need cells outside the region.
+    pub fn split_n_times_region(
+        mesh: &Mesh,
+        seed: u64,
+        random_range: (f64, f64),
+        times: u32,
+        region: Region,
+    ) -> Mesh {
+        assert!(
+            mesh.get_edge_policy().is_constant(),
+            "Region splitting only supports constant edges"
+        );
+        if times == 0 {
+            return mesh.crop(region.x, region.y, region.width, region.height);
+        }
+        let parent_region = region.get_parent();
+        let parent =
+            MeshSplitter::split_n_times_region(mesh, seed, random_range, times - 1, parent_region);
+        let world = Region::new(
+            0,
+            0,
+            mesh.get_width() << (times - 1),
+            mesh.get_height() << (times - 1),
+        );
+        MeshSplitter::split_region_seeded(
+            &parent,
+            parent_region,
+            world,
+            seed,
+            times - 1,
+            random_range,
+            region,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -345,6 +649,261 @@ mod tests {
         );
     }
 
+    fn root() -> Mesh {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.9, 0.8, 0.7, 1.0]));
+        mesh
+    }
+
+    #[test]
+    fn test_region_get_parent() {
+        assert_eq!(
+            Region::new(4, 4, 4, 4).get_parent(),
+            Region::new(1, 1, 4, 4)
+        );
+        assert_eq!(
+            Region::new(0, 3, 2, 2).get_parent(),
+            Region::new(-1, 0, 3, 4)
+        );
+    }
+
+    #[test]
+    fn split_seeded_should_be_reproducible() {
+        let a = MeshSplitter::split_n_times_seeded(&root(), 7, (0.0, 1.0), 4);
+        let b = MeshSplitter::split_n_times_seeded(&root(), 7, (0.0, 1.0), 4);
+        let c = MeshSplitter::split_n_times_seeded(&root(), 8, (0.0, 1.0), 4);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn split_region_should_match_whole_mesh() {
+        let whole = MeshSplitter::split_n_times_seeded(&root(), 7, (0.0, 1.0), 5);
+
+        for region in [
+            Region::new(0, 0, 64, 64),
+            Region::new(0, 0, 16, 16),
+            Region::new(16, 48, 16, 16),
+            Region::new(7, 21, 5, 5),
+            Region::new(61, 0, 3, 3),
+            Region::new(60, 60, 8, 8),
+            Region::new(5, 30, 11, 3),
+        ]
+        .iter()
+        {
+            let actual = MeshSplitter::split_n_times_region(&root(), 7, (0.0, 1.0), 5, *region);
+
+            assert_eq!(
+                actual,
+                whole.crop(region.x, region.y, region.width, region.height)
+            );
+        }
+    }
+
+    #[test]
+    fn adjacent_regions_should_not_have_seams() {
+        let left = MeshSplitter::split_n_times_region(
+            &root(),
+            7,
+            (0.0, 1.0),
+            4,
+            Region::new(0, 0, 16, 16),
+        );
+        let right = MeshSplitter::split_n_times_region(
+            &root(),
+            7,
+            (0.0, 1.0),
+            4,
+            Region::new(16, 0, 16, 16),
+        );
+        let whole = MeshSplitter::split_n_times_seeded(&root(), 7, (0.0, 1.0), 4);
+
+        for y in 0..16 {
+            assert_eq!(left.get_z(15, y), whole.get_z(15, y));
+            assert_eq!(right.get_z(0, y), whole.get_z(16, y));
+        }
+    }
+
+    #[test]
+    fn split_seeded_should_retain_downhill_property() {
+        use downhill_map::DownhillMap;
+
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+
+        mesh = MeshSplitter::split_n_times_seeded(&mesh, 3, (0.1, 0.5), 8);
+        assert_eq!(mesh.get_width(), 256);
+        let downhill = DownhillMap::new(&mesh);
+        assert_eq!(downhill.all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn constant_schedule_should_match_split_n_times() {
+        let random_range = (0.1, 0.5);
+
+        let expected = MeshSplitter::split_n_times(&root(), &mut get_rng(), random_range, 3);
+        let actual = MeshSplitter::split_n_times_with_ranges(
+            &root(),
+            &mut get_rng(),
+            &[random_range, random_range, random_range],
+            None,
+        );
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn schedule_should_be_called_for_each_level() {
+        use std::cell::RefCell;
+
+        let levels = RefCell::new(vec![]);
+        let actual = MeshSplitter::split_n_times_scheduled(
+            &root(),
+            &mut get_rng(),
+            |level| {
+                levels.borrow_mut().push(level);
+                (0.0, 1.0 / (level + 1) as f64)
+            },
+            3,
+            None,
+        );
+
+        assert_eq!(actual.get_width(), 16);
+        assert_eq!(*levels.borrow(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_get_masked_range() {
+        let mask = na::DMatrix::from_row_slice(2, 2, &[0.0, 0.5, 1.0, 2.0]);
+
+        assert_eq!(
+            MeshSplitter::get_masked_range((0.2, 0.6), &mask, &Mesh::new(4, 0.0), 1, 1),
+            (0.2, 0.2)
+        );
+        assert_eq!(
+            MeshSplitter::get_masked_range((0.2, 0.6), &mask, &Mesh::new(4, 0.0), 0, 3),
+            (0.2, 0.4)
+        );
+        assert_eq!(
+            MeshSplitter::get_masked_range((0.2, 0.6), &mask, &Mesh::new(4, 0.0), 3, 0),
+            (0.2, 0.6)
+        );
+        assert_eq!(
+            MeshSplitter::get_masked_range((0.2, 0.6), &mask, &Mesh::new(4, 0.0), 2, 2),
+            (0.2, 0.6)
+        );
+    }
+
+    #[test]
+    fn zero_mask_should_remove_randomness() {
+        let mask = na::DMatrix::zeros(4, 4);
+
+        let a = MeshSplitter::split_n_times_with_ranges(
+            &root(),
+            &mut Box::new(StdRng::seed_from_u64(1)),
+            &[(0.1, 0.5), (0.2, 0.9)],
+            Some(&mask),
+        );
+        let b = MeshSplitter::split_n_times_with_ranges(
+            &root(),
+            &mut Box::new(StdRng::seed_from_u64(2)),
+            &[(0.1, 0.5), (0.2, 0.9)],
+            Some(&mask),
+        );
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn scheduled_split_should_retain_downhill_property() {
+        use downhill_map::DownhillMap;
+
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        let mut mask = na::DMatrix::zeros(8, 8);
+        for x in 4..8 {
+            for y in 0..8 {
+                mask[(x, y)] = 1.0;
+            }
+        }
+        let mut rng = Box::new(StdRng::seed_from_u64(1));
+
+        mesh = MeshSplitter::split_n_times_scheduled(
+            &mesh,
+            &mut rng,
+            |level| (0.05 + 0.05 * level as f64, 0.9),
+            8,
+            Some(&mask),
+        );
+        assert_eq!(mesh.get_width(), 256);
+        let downhill = DownhillMap::new(&mesh);
+        assert_eq!(downhill.all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn rectangular_split_should_retain_downhill_property() {
+        use downhill_map::DownhillMap;
+
+        let mut mesh = Mesh::with_size(3, 2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[1.0, 0.9, 0.8, 1.0, 0.7, 0.9],
+        ));
+
+        let mesh = MeshSplitter::split_n_times(&mesh, &mut get_rng(), (0.1, 0.5), 4);
+
+        assert_eq!(mesh.get_width(), 48);
+        assert_eq!(mesh.get_height(), 32);
+        assert_eq!(DownhillMap::new(&mesh).all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn test_split_to_size_crop() {
+        use downhill_map::DownhillMap;
+
+        let mut mesh = Mesh::with_size(3, 2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[1.0, 0.9, 0.8, 1.0, 0.7, 0.9],
+        ));
+        let mut rng = Box::new(StdRng::seed_from_u64(1));
+
+        let actual = MeshSplitter::split_to_size(&mesh, &mut rng, (0.1, 0.5), 90, 60, Fit::Crop);
+
+        assert_eq!(actual.get_width(), 90);
+        assert_eq!(actual.get_height(), 60);
+        assert_eq!(DownhillMap::new(&actual).all_cells_have_downhill(), true);
+    }
+
+    #[test]
+    fn test_split_to_size_resample() {
+        let mut rng = Box::new(StdRng::seed_from_u64(1));
+
+        let actual =
+            MeshSplitter::split_to_size(&root(), &mut rng, (0.1, 0.5), 30, 20, Fit::Resample);
+
+        assert_eq!(actual.get_width(), 30);
+        assert_eq!(actual.get_height(), 20);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot split an empty mesh to size")]
+    fn split_to_size_should_reject_empty_mesh() {
+        let mut rng = Box::new(StdRng::seed_from_u64(1));
+
+        MeshSplitter::split_to_size(
+            &Mesh::with_size(0, 2, 0.0),
+            &mut rng,
+            (0.1, 0.5),
+            4,
+            4,
+            Fit::Crop,
+        );
+    }
+
     #[test]
     fn mesh_splitter_should_retain_downhill_property() {
         use downhill_map::DownhillMap;
@@ -0,0 +1,193 @@
+use downhill_map::{get_slope, DownhillMap};
+use mesh::Mesh;
+use utils::float_ordering;
+
+#[derive(Debug, PartialEq)]
+pub struct MultipleFlowMap {
+    flow: na::DMatrix<f64>,
+}
+
+impl MultipleFlowMap {
+    pub fn new(width: usize) -> MultipleFlowMap {
+        MultipleFlowMap::with_size(width, width)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> MultipleFlowMap {
+        MultipleFlowMap {
+            flow: na::DMatrix::zeros(width, height),
+        }
+    }
+
+    pub fn get_flow(&self, x: i32, y: i32) -> f64 {
+        self.flow[(x as usize, y as usize)]
+    }
+
+    pub fn get_flow_matrix(&self) -> &na::DMatrix<f64> {
+        &self.flow
+    }
+
+    pub fn get_max_flow(&self) -> f64 {
+        *self.flow.iter().max_by(float_ordering).unwrap()
+    }
+
+    pub fn set_flow(&mut self, flow: na::DMatrix<f64>) {
+        self.flow = flow;
+    }
+
+    /// Each cell passes its water to all downhill neighbours in proportion to slope raised to
+    /// `exponent` (Freeman uses 1.1). Water passed out of bounds leaves the map, and water in a
+    /// cell with no downhill stays there.
+    pub fn from(mesh: &Mesh, downhill_map: &DownhillMap, exponent: f64) -> MultipleFlowMap {
+        let (width, height) = mesh.get_shape();
+        MultipleFlowMap::from_precipitation(
+            mesh,
+            downhill_map,
+            exponent,
+            &na::DMatrix::repeat(width, height, 1.0),
+        )
+    }
+
+    /// Each cell receives the amount of water in the corresponding cell of `precipitation`,
+    /// which must be the same size as the mesh.
+    pub fn from_precipitation(
+        mesh: &Mesh,
+        downhill_map: &DownhillMap,
+        exponent: f64,
+        precipitation: &na::DMatrix<f64>,
+    ) -> MultipleFlowMap {
+        assert_eq!(
+            precipitation.shape(),
+            mesh.get_shape(),
+            "Precipitation must be the same size as the mesh"
+        );
+        let (width, height) = mesh.get_shape();
+        let mut out = MultipleFlowMap::with_size(width, height);
+        out.rain_on_all(mesh, downhill_map, exponent, precipitation);
+        out
+    }
+
+    // Water only moves to strictly lower cells, so visiting cells from highest to lowest means
+    // every cell has received all of its water before passing it on
+    fn get_cells_highest_first(mesh: &Mesh) -> Vec<(i32, i32)> {
+        let mut out = Vec::with_capacity(mesh.get_cell_count());
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                out.push((x, y));
+            }
+        }
+        out.sort_by(|a, b| {
+            mesh.get_z(b.0, b.1)
+                .partial_cmp(&mesh.get_z(a.0, a.1))
+                .unwrap()
+        });
+        out
+    }
+
+    fn rain_on_all(
+        &mut self,
+        mesh: &Mesh,
+        downhill_map: &DownhillMap,
+        exponent: f64,
+        precipitation: &na::DMatrix<f64>,
+    ) {
+        let directions = downhill_map.get_neighbourhood().get_directions();
+        self.flow = precipitation.clone();
+        for (x, y) in MultipleFlowMap::get_cells_highest_first(mesh) {
+            let candidates = downhill_map.get_candidates(x, y);
+            let weights: Vec<f64> = candidates
+                .iter()
+                .map(|candidate| get_slope(mesh, x, y, directions[*candidate]).powf(exponent))
+                .collect();
+            let total: f64 = weights.iter().sum();
+            let flow = self.get_flow(x, y);
+            for (candidate, weight) in candidates.iter().zip(weights.iter()) {
+                if let Some((nx, ny)) = mesh.get_neighbour(x, y, directions[*candidate]) {
+                    self.flow[(nx as usize, ny as usize)] += flow * weight / total;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use downhill_map::Neighbourhood;
+
+    fn assert_flow_eq(actual: &MultipleFlowMap, expected: &[f64]) {
+        let expected =
+            na::DMatrix::from_row_slice(actual.flow.nrows(), actual.flow.ncols(), expected);
+        for (a, e) in actual.flow.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9, "{} != {}", actual.flow, expected);
+        }
+    }
+
+    #[test]
+    fn test_from() {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.1]));
+        let downhill_map = DownhillMap::new(&mesh);
+
+        let actual = MultipleFlowMap::from(&mesh, &downhill_map, 1.0);
+
+        assert_flow_eq(&actual, &[1.0, 4.0 / 3.0, 5.0 / 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_from_with_exponent() {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.1]));
+        let downhill_map = DownhillMap::new(&mesh);
+
+        let actual = MultipleFlowMap::from(&mesh, &downhill_map, 2.0);
+
+        assert_flow_eq(&actual, &[1.0, 1.2, 1.8, 4.0]);
+    }
+
+    #[test]
+    fn test_from_out_of_bounds() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.4, 0.4, 0.4]));
+        let downhill_map = DownhillMap::new(&mesh);
+
+        let actual = MultipleFlowMap::from(&mesh, &downhill_map, 1.0);
+
+        assert_flow_eq(&actual, &[1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_from_diagonal() {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            2,
+            2,
+            &[0.5, 0.4, 0.4, 0.5 - 2.0f64.sqrt() * 0.1],
+        ));
+        let downhill_map = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+
+        let actual = MultipleFlowMap::from(&mesh, &downhill_map, 1.0);
+
+        assert_flow_eq(&actual, &[1.0, 4.0 / 3.0, 4.0 / 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_from_precipitation() {
+        let mut mesh = Mesh::new(2, 1.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.2, 0.1]));
+        let downhill_map = DownhillMap::new(&mesh);
+        let precipitation = na::DMatrix::from_row_slice(2, 2, &[3.0, 0.0, 1.0, 0.5]);
+
+        let actual = MultipleFlowMap::from_precipitation(&mesh, &downhill_map, 1.0, &precipitation);
+
+        assert_flow_eq(&actual, &[3.0, 1.0, 3.0, 4.5]);
+    }
+
+    #[test]
+    fn test_max_flow() {
+        let mut flow_map = MultipleFlowMap::new(2);
+        flow_map.set_flow(na::DMatrix::from_row_slice(2, 2, &[0.5, 2.5, 1.5, 1.0]));
+        assert_eq!(flow_map.get_max_flow(), 2.5);
+    }
+
+}
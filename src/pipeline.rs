@@ -0,0 +1,522 @@
+use depression_filler::DepressionFiller;
+use downhill_map::DownhillMap;
+use edge_policy::{Boundary, EdgePolicy};
+use erosion::{Erosion, ErosionConfig};
+use flow_map::FlowMap;
+use isometric::terrain::{Edge, Node};
+use mesh::Mesh;
+use mesh_splitter::MeshSplitter;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use river_runner::get_junctions_and_rivers_from_flow_map;
+use scale::Scale;
+use single_downhill_map::{RandomDownhillMap, SingleDownhillMap};
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde_json;
+#[cfg(feature = "serde")]
+use toml;
+
+/// One step of a `Pipeline`. In TOML or JSON each stage is a table with a `stage` key naming
+/// the variant in snake case, e.g. `stage = "fill_depressions"`, and a key for each field.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "stage", rename_all = "snake_case")
+)]
+pub enum Stage {
+    /// See `MeshSplitter::split_n_times`.
+    Split {
+        levels: u32,
+        random_range: (f64, f64),
+    },
+    /// See `Erosion::erode` and `ErosionConfig::new`. Settings that are not given come from
+    /// `ErosionConfig::with_threshold`, so `threshold` is raised to 1 if it is below 1.
+    /// `strength` is a constant fraction. Every cell must have a downhill neighbour.
+    Erode {
+        threshold: f64,
+        samples: usize,
+        #[cfg_attr(feature = "serde", serde(default))]
+        strength: Option<f64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        max_erosion: Option<f64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        repeat: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        sea_level: Option<f64>,
+    },
+    /// See `DepressionFiller::fill`.
+    FillDepressions { epsilon: f64 },
+    /// Linearly maps the lowest and highest cells to `min` and `max`. Constant edges are
+    /// mapped in the same way, so water leaves the mesh where it did before.
+    Rescale { min: f64, max: f64 },
+    /// Routes flow along a random downhill direction from each cell. Every cell must have a
+    /// downhill neighbour, which `FillDepressions` guarantees.
+    Flow,
+    /// See `river_runner::get_junctions_and_rivers_from_flow_map`. Uses the flow from the last
+    /// `Flow` stage if the mesh has not changed since, and otherwise computes flow as `Flow`
+    /// does.
+    Rivers {
+        threshold: f64,
+        sea_level: f64,
+        flow_to_width: (f64, f64),
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PipelineError {
+    Parse(String),
+    InvalidStage { stage: usize, message: String },
+    NoDownhill { stage: usize },
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::Parse(message) => write!(f, "Could not parse pipeline: {}", message),
+            PipelineError::InvalidStage { stage, message } => {
+                write!(f, "Stage {} is invalid: {}", stage, message)
+            }
+            PipelineError::NoDownhill { stage } => write!(
+                f,
+                "Stage {} needs every cell to have a downhill neighbour",
+                stage
+            ),
+        }
+    }
+}
+
+/// Everything a pipeline produced. Flow and rivers are only present if a stage computed them.
+pub struct PipelineOutput {
+    mesh: Mesh,
+    flow_map: Option<FlowMap>,
+    junctions: Option<Vec<Node>>,
+    rivers: Option<Vec<Edge>>,
+}
+
+impl PipelineOutput {
+    pub fn get_mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    pub fn get_flow_map(&self) -> Option<&FlowMap> {
+        self.flow_map.as_ref()
+    }
+
+    pub fn get_junctions(&self) -> Option<&Vec<Node>> {
+        self.junctions.as_ref()
+    }
+
+    pub fn get_rivers(&self) -> Option<&Vec<Edge>> {
+        self.rivers.as_ref()
+    }
+}
+
+/// An ordered list of stages run against a single cell at height 1, with out of bounds z 0.
+/// All randomness comes from one generator seeded when the pipeline is run, so a world is
+/// reproducible from the pipeline and seed alone.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Stage>) -> Pipeline {
+        Pipeline { stages }
+    }
+
+    pub fn get_stages(&self) -> &Vec<Stage> {
+        &self.stages
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_toml(text: &str) -> Result<Pipeline, PipelineError> {
+        toml::from_str(text).map_err(|error| PipelineError::Parse(error.to_string()))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_json(text: &str) -> Result<Pipeline, PipelineError> {
+        serde_json::from_str(text).map_err(|error| PipelineError::Parse(error.to_string()))
+    }
+
+    pub fn run(&self, seed: u64) -> Result<PipelineOutput, PipelineError> {
+        let mut rng = Box::new(StdRng::seed_from_u64(seed));
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        let mut out = PipelineOutput {
+            mesh,
+            flow_map: None,
+            junctions: None,
+            rivers: None,
+        };
+        // Downhill map and flow for the current mesh, cleared whenever the mesh changes
+        let mut flow: Option<(Box<SingleDownhillMap>, FlowMap)> = None;
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let invalid = |message: &str| PipelineError::InvalidStage {
+                stage: index,
+                message: message.to_string(),
+            };
+            match stage {
+                Stage::Split {
+                    levels,
+                    random_range,
+                } => {
+                    out.mesh =
+                        MeshSplitter::split_n_times(&out.mesh, &mut rng, *random_range, *levels);
+                    flow = None;
+                }
+                Stage::Erode {
+                    threshold,
+                    samples,
+                    strength,
+                    max_erosion,
+                    repeat,
+                    sea_level,
+                } => {
+                    // Erosion routes flow with a RandomDownhillMap, which panics otherwise
+                    if !DownhillMap::new(&out.mesh).all_cells_have_downhill() {
+                        return Err(PipelineError::NoDownhill { stage: index });
+                    }
+                    let defaults = ErosionConfig::with_threshold(*threshold);
+                    let max_erosion = max_erosion.unwrap_or(defaults.get_max_erosion());
+                    let min_flow = defaults.get_min_flow();
+                    let sea_level = sea_level.unwrap_or(defaults.get_sea_level());
+                    let strength = *strength;
+                    let config = ErosionConfig::new(
+                        move |flow| strength.unwrap_or_else(|| defaults.get_strength(flow)),
+                        max_erosion,
+                        min_flow,
+                        *repeat,
+                        sea_level,
+                    );
+                    let mesh = out.mesh.clone();
+                    out.mesh = Erosion::erode(mesh, &mut rng, &config, *samples)
+                        .get_mesh()
+                        .clone();
+                    flow = None;
+                }
+                Stage::FillDepressions { epsilon } => {
+                    if *epsilon <= 0.0 {
+                        return Err(invalid("Epsilon must be positive"));
+                    }
+                    out.mesh = DepressionFiller::fill(&out.mesh, *epsilon);
+                    flow = None;
+                }
+                Stage::Rescale { min, max } => {
+                    let (from_min, from_max) = {
+                        let z = out.mesh.get_z_vector();
+                        (z.min(), z.max())
+                    };
+                    if from_min == from_max {
+                        return Err(invalid("Cannot rescale a flat mesh"));
+                    }
+                    let scale = Scale::new((from_min, from_max), (*min, *max));
+                    out.mesh = out.mesh.rescale(&scale);
+                    let edge_policy = Pipeline::rescale_edges(out.mesh.get_edge_policy(), &scale);
+                    out.mesh.set_edge_policy(edge_policy);
+                    flow = None;
+                }
+                Stage::Flow => {
+                    flow = Some(Pipeline::get_flow(&out.mesh, &mut rng, index)?);
+                    out.flow_map = flow.as_ref().map(|(_, flow_map)| flow_map.clone());
+                }
+                Stage::Rivers {
+                    threshold,
+                    sea_level,
+                    flow_to_width,
+                } => {
+                    if flow.is_none() {
+                        flow = Some(Pipeline::get_flow(&out.mesh, &mut rng, index)?);
+                        out.flow_map = flow.as_ref().map(|(_, flow_map)| flow_map.clone());
+                    }
+                    let (downhill_map, flow_map) = flow.as_ref().unwrap();
+                    let (junctions, rivers) = get_junctions_and_rivers_from_flow_map(
+                        &out.mesh,
+                        *threshold,
+                        *sea_level,
+                        *flow_to_width,
+                        downhill_map,
+                        flow_map,
+                        None,
+                    );
+                    out.junctions = Some(junctions);
+                    out.rivers = Some(rivers);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn rescale_edges(edge_policy: EdgePolicy, scale: &Scale) -> EdgePolicy {
+        let rescale = |boundary| match boundary {
+            Boundary::Constant(z) => Boundary::Constant(scale.scale(z)),
+            other => other,
+        };
+        EdgePolicy::new(
+            rescale(edge_policy.get_west()),
+            rescale(edge_policy.get_north()),
+            rescale(edge_policy.get_east()),
+            rescale(edge_policy.get_south()),
+        )
+    }
+
+    fn get_flow<R: Rng>(
+        mesh: &Mesh,
+        rng: &mut Box<R>,
+        stage: usize,
+    ) -> Result<(Box<SingleDownhillMap>, FlowMap), PipelineError> {
+        let downhill_map = DownhillMap::new(mesh);
+        if !downhill_map.all_cells_have_downhill() {
+            return Err(PipelineError::NoDownhill { stage });
+        }
+        let downhill_map: Box<SingleDownhillMap> =
+            Box::new(RandomDownhillMap::new(&downhill_map, rng));
+        let flow_map = FlowMap::from_topological(mesh, &downhill_map);
+        Ok((downhill_map, flow_map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn pipeline() -> Pipeline {
+        Pipeline::new(vec![
+            Stage::Split {
+                levels: 4,
+                random_range: (0.0, 1.0),
+            },
+            Stage::Erode {
+                threshold: 8.0,
+                samples: 2,
+                strength: None,
+                max_erosion: None,
+                repeat: false,
+                sea_level: None,
+            },
+            Stage::FillDepressions { epsilon: 1e-6 },
+            Stage::Rescale {
+                min: -10.0,
+                max: 100.0,
+            },
+            Stage::Rivers {
+                threshold: 4.0,
+                sea_level: 0.0,
+                flow_to_width: (0.1, 0.5),
+            },
+        ])
+    }
+
+    #[test]
+    fn test_run() {
+        let output = pipeline().run(1).unwrap();
+
+        let mesh = output.get_mesh();
+        assert_eq!(mesh.get_shape(), (16, 16));
+        assert_eq!(mesh.get_z_vector().min(), -10.0);
+        assert_eq!(mesh.get_z_vector().max(), 100.0);
+        assert_eq!(DownhillMap::new(mesh).all_cells_have_downhill(), true);
+        assert!(output.get_flow_map().is_some());
+        assert!(!output.get_rivers().unwrap().is_empty());
+        assert_eq!(
+            output.get_junctions().unwrap().len(),
+            output.get_rivers().unwrap().len() * 2
+        );
+    }
+
+    #[test]
+    fn run_should_be_reproducible_from_seed() {
+        let a = pipeline().run(7).unwrap();
+        let b = pipeline().run(7).unwrap();
+        let c = pipeline().run(8).unwrap();
+
+        assert_eq!(a.get_mesh(), b.get_mesh());
+        assert_eq!(a.get_flow_map(), b.get_flow_map());
+        assert_eq!(a.get_rivers(), b.get_rivers());
+        assert!(a.get_mesh() != c.get_mesh());
+    }
+
+    #[test]
+    fn test_flow() {
+        let pipeline = Pipeline::new(vec![
+            Stage::Split {
+                levels: 2,
+                random_range: (0.0, 1.0),
+            },
+            Stage::Flow,
+        ]);
+
+        let output = pipeline.run(0).unwrap();
+
+        let flow = output.get_flow_map().unwrap().get_flow_matrix();
+        assert_eq!(flow.shape(), (4, 4));
+        assert_eq!(flow.min(), 1.0);
+        assert!(output.get_rivers().is_none());
+    }
+
+    #[test]
+    fn flow_should_reject_mesh_without_downhill() {
+        let pipeline = Pipeline::new(vec![Stage::Rescale { min: 1.0, max: 2.0 }, Stage::Flow]);
+
+        assert_eq!(
+            pipeline.run(0).err(),
+            Some(PipelineError::InvalidStage {
+                stage: 0,
+                message: "Cannot rescale a flat mesh".to_string()
+            })
+        );
+
+        let pipeline = Pipeline::new(vec![
+            Stage::Split {
+                levels: 1,
+                random_range: (0.0, 0.0),
+            },
+            Stage::Flow,
+        ]);
+
+        assert_eq!(
+            pipeline.run(0).err(),
+            Some(PipelineError::NoDownhill { stage: 1 })
+        );
+
+        let pipeline = Pipeline::new(vec![
+            Stage::Split {
+                levels: 1,
+                random_range: (0.0, 0.0),
+            },
+            Stage::Erode {
+                threshold: 1.0,
+                samples: 1,
+                strength: None,
+                max_erosion: None,
+                repeat: false,
+                sea_level: None,
+            },
+        ]);
+
+        assert_eq!(
+            pipeline.run(0).err(),
+            Some(PipelineError::NoDownhill { stage: 1 })
+        );
+    }
+
+    #[test]
+    fn erode_should_default_to_erosion_config_with_threshold() {
+        let erode = |strength| {
+            Pipeline::new(vec![
+                Stage::Split {
+                    levels: 3,
+                    random_range: (0.0, 1.0),
+                },
+                Stage::Erode {
+                    threshold: 0.5,
+                    samples: 2,
+                    strength,
+                    max_erosion: None,
+                    repeat: false,
+                    sea_level: None,
+                },
+            ])
+            .run(3)
+            .unwrap()
+        };
+
+        let mut rng = Box::new(StdRng::seed_from_u64(3));
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        let mesh = MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 3);
+        let config = ErosionConfig::with_threshold(0.5);
+        let expected = Erosion::erode(mesh, &mut rng, &config, 2);
+
+        assert_eq!(erode(None).get_mesh(), expected.get_mesh());
+        assert_eq!(erode(Some(0.1)).get_mesh(), expected.get_mesh());
+    }
+
+    #[test]
+    fn fill_should_reject_non_positive_epsilon() {
+        let pipeline = Pipeline::new(vec![Stage::FillDepressions { epsilon: 0.0 }]);
+
+        match pipeline.run(0) {
+            Err(PipelineError::InvalidStage { stage: 0, .. }) => (),
+            _ => panic!("Expected InvalidStage"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_from_toml() {
+        let text = r#"
+            [[stages]]
+            stage = "split"
+            levels = 4
+            random_range = [0.0, 1.0]
+
+            [[stages]]
+            stage = "erode"
+            threshold = 8.0
+            samples = 2
+
+            [[stages]]
+            stage = "fill_depressions"
+            epsilon = 1e-6
+
+            [[stages]]
+            stage = "rescale"
+            min = -10.0
+            max = 100.0
+
+            [[stages]]
+            stage = "rivers"
+            threshold = 4.0
+            sea_level = 0.0
+            flow_to_width = [0.1, 0.5]
+        "#;
+
+        assert_eq!(Pipeline::from_toml(text), Ok(pipeline()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_from_json() {
+        let text = r#"{"stages": [
+            {"stage": "split", "levels": 2, "random_range": [0.5, 0.5]},
+            {"stage": "erode", "threshold": 4, "samples": 1, "strength": 0.2, "repeat": true},
+            {"stage": "flow"}
+        ]}"#;
+
+        let expected = Pipeline::new(vec![
+            Stage::Split {
+                levels: 2,
+                random_range: (0.5, 0.5),
+            },
+            Stage::Erode {
+                threshold: 4.0,
+                samples: 1,
+                strength: Some(0.2),
+                max_erosion: None,
+                repeat: true,
+                sea_level: None,
+            },
+            Stage::Flow,
+        ]);
+        assert_eq!(Pipeline::from_json(text), Ok(expected));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn parse_should_reject_unknown_stage() {
+        let text = "[[stages]]\nstage = \"terraform\"\n";
+
+        match Pipeline::from_toml(text) {
+            Err(PipelineError::Parse(_)) => (),
+            other => panic!("Expected Parse error, got {:?}", other),
+        }
+    }
+
+}
@@ -0,0 +1,210 @@
+use mesh::Mesh;
+
+pub struct OrographicPrecipitation {
+    wind: (f64, f64),
+    sea_level: f64,
+    capacity: f64,
+    background: f64,
+    orographic: f64,
+}
+
+impl OrographicPrecipitation {
+    /// Air blows across the mesh along `wind` carrying moisture. Over the sea, and arriving from
+    /// off the mesh across an edge that is below sea level or not `Boundary::Constant`, it holds
+    /// `capacity`. Over land it drops `background` of its moisture in every cell, plus
+    /// `orographic` for every unit it is forced to rise, so ridges are wet on the windward side
+    /// and dry behind.
+    pub fn new(
+        wind: (f64, f64),
+        sea_level: f64,
+        capacity: f64,
+        background: f64,
+        orographic: f64,
+    ) -> OrographicPrecipitation {
+        assert!(wind != (0.0, 0.0), "Wind must have a direction");
+        OrographicPrecipitation {
+            wind,
+            sea_level,
+            capacity,
+            background,
+            orographic,
+        }
+    }
+
+    fn get_upwind(&self) -> [((i32, i32), f64); 2] {
+        let (wx, wy) = self.wind;
+        [
+            ((-wx.signum() as i32, 0), wx.abs()),
+            ((0, -wy.signum() as i32), wy.abs()),
+        ]
+    }
+
+    // Every cell comes after its upwind neighbours
+    fn get_cells_upwind_first(&self, mesh: &Mesh) -> Vec<(i32, i32)> {
+        let mut out = Vec::with_capacity(mesh.get_cell_count());
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                out.push((x, y));
+            }
+        }
+        let (wx, wy) = self.wind;
+        out.sort_by(|a, b| {
+            let a = a.0 as f64 * wx + a.1 as f64 * wy;
+            let b = b.0 as f64 * wx + b.1 as f64 * wy;
+            a.partial_cmp(&b).unwrap()
+        });
+        out
+    }
+
+    fn get_moisture(&self, mesh: &Mesh, moisture: &na::DMatrix<f64>, x: i32, y: i32) -> f64 {
+        if mesh.in_bounds(x, y) {
+            moisture[(x as usize, y as usize)]
+        } else if mesh.resolve(x, y).is_some() || mesh.get_z(x, y) < self.sea_level {
+            // Cells that edges wrap, clamp or mirror to may not have been reached yet
+            self.capacity
+        } else {
+            0.0
+        }
+    }
+
+    pub fn compute(&self, mesh: &Mesh) -> na::DMatrix<f64> {
+        let (width, height) = mesh.get_shape();
+        let mut moisture = na::DMatrix::zeros(width, height);
+        let mut out = na::DMatrix::zeros(width, height);
+        let upwind = self.get_upwind();
+        let total_weight: f64 = upwind.iter().map(|(_, weight)| weight).sum();
+
+        for (x, y) in self.get_cells_upwind_first(mesh) {
+            let mut incoming = 0.0;
+            let mut upwind_z = 0.0;
+            for ((dx, dy), weight) in upwind.iter() {
+                if *weight > 0.0 {
+                    incoming += weight * self.get_moisture(mesh, &moisture, x + dx, y + dy);
+                    upwind_z += weight * mesh.get_z(x + dx, y + dy);
+                }
+            }
+            incoming /= total_weight;
+            upwind_z /= total_weight;
+
+            let z = mesh.get_z(x, y);
+            let rise = (z - upwind_z).max(0.0);
+            let fraction = (self.background + self.orographic * rise).min(1.0);
+            let rain = incoming * fraction;
+
+            out[(x as usize, y as usize)] = rain;
+            moisture[(x as usize, y as usize)] = if z < self.sea_level {
+                self.capacity
+            } else {
+                incoming - rain
+            };
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use edge_policy::{Boundary, EdgePolicy};
+
+    fn ridge() -> Mesh {
+        let mut mesh = Mesh::new(5, 0.0);
+        let mut z = na::DMatrix::zeros(5, 5);
+        for y in 0..5 {
+            z[(0, y)] = 0.0;
+            z[(1, y)] = 0.2;
+            z[(2, y)] = 0.6;
+            z[(3, y)] = 0.3;
+            z[(4, y)] = 0.2;
+        }
+        mesh.set_z_vector(z);
+        mesh
+    }
+
+    #[test]
+    fn test_compute() {
+        let precipitation = OrographicPrecipitation::new((1.0, 0.0), 0.1, 1.0, 0.1, 1.0);
+
+        let actual = precipitation.compute(&ridge());
+
+        let expected = [0.1, 0.3, 0.35, 0.035, 0.0315];
+        for x in 0..5 {
+            for y in 0..5 {
+                assert!((actual[(x, y)] - expected[x]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn ridge_should_cast_rain_shadow() {
+        let precipitation = OrographicPrecipitation::new((1.0, 0.0), 0.1, 1.0, 0.0, 1.0);
+
+        let actual = precipitation.compute(&ridge());
+
+        assert!(actual[(2, 2)] > actual[(3, 2)]);
+        assert_eq!(actual[(3, 2)], 0.0);
+        assert_eq!(actual[(4, 2)], 0.0);
+    }
+
+    #[test]
+    fn sea_should_replenish_moisture() {
+        let mut mesh = ridge();
+        for y in 0..5 {
+            mesh.set_z(3, y, 0.0);
+            mesh.set_z(4, y, 0.5);
+        }
+        let precipitation = OrographicPrecipitation::new((1.0, 0.0), 0.1, 1.0, 0.0, 1.0);
+
+        let actual = precipitation.compute(&mesh);
+
+        assert!((actual[(4, 2)] - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wind_direction_should_change_wet_side() {
+        let precipitation = OrographicPrecipitation::new((-1.0, 0.0), 0.1, 1.0, 0.0, 1.0);
+
+        let actual = precipitation.compute(&ridge());
+
+        assert_eq!(actual[(0, 2)], 0.0);
+        assert_eq!(actual[(1, 2)], 0.0);
+        assert!(actual[(2, 2)] > 0.0);
+        assert!(actual[(3, 2)] > 0.0);
+    }
+
+    #[test]
+    fn moisture_should_arrive_across_wrapped_edge() {
+        let mut mesh = Mesh::with_edge_policy(
+            5,
+            5,
+            EdgePolicy::new(
+                Boundary::Wrap,
+                Boundary::Constant(1.0),
+                Boundary::Wrap,
+                Boundary::Constant(1.0),
+            ),
+        );
+        mesh.set_z_vector(na::DMatrix::repeat(5, 5, 0.5));
+        let precipitation = OrographicPrecipitation::new((1.0, 0.0), 0.1, 1.0, 0.5, 1.0);
+
+        let actual = precipitation.compute(&mesh);
+
+        for y in 0..5 {
+            assert!((actual[(0, y)] - 0.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn no_moisture_should_arrive_from_land() {
+        let mut mesh = Mesh::new(5, 1.0);
+        mesh.set_z_vector(na::DMatrix::repeat(5, 5, 0.5));
+        let precipitation = OrographicPrecipitation::new((1.0, 1.0), 0.1, 1.0, 0.5, 1.0);
+
+        let actual = precipitation.compute(&mesh);
+
+        assert_eq!(actual, na::DMatrix::zeros(5, 5));
+    }
+
+}
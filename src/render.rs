@@ -0,0 +1,464 @@
+use flow_map::FlowMap;
+use isometric::terrain::{Edge, Node};
+use mesh::Mesh;
+use png::{BitDepth, ColorType, Encoder, EncodingError, HasParameters};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+pub type Rgb = [u8; 3];
+
+/// Colours for a range of values, linearly interpolated between stops. Values beyond the first
+/// or last stop take the colour of that stop.
+pub struct ColourRamp {
+    stops: Vec<(f64, Rgb)>,
+}
+
+impl ColourRamp {
+    /// `stops` must be sorted by value.
+    pub fn new(stops: Vec<(f64, Rgb)>) -> ColourRamp {
+        assert!(!stops.is_empty(), "Colour ramp must have at least one stop");
+        ColourRamp { stops }
+    }
+
+    fn from_fractions(min: f64, max: f64, fractions: &[(f64, Rgb)]) -> ColourRamp {
+        ColourRamp::new(
+            fractions
+                .iter()
+                .map(|(fraction, colour)| (min + (max - min) * fraction, *colour))
+                .collect(),
+        )
+    }
+
+    /// Lowland green through brown to snow, from `min` to `max`.
+    pub fn hypsometric(min: f64, max: f64) -> ColourRamp {
+        ColourRamp::from_fractions(
+            min,
+            max,
+            &[
+                (0.0, [70, 130, 70]),
+                (0.3, [170, 180, 100]),
+                (0.6, [150, 115, 75]),
+                (0.85, [190, 185, 180]),
+                (1.0, [255, 255, 255]),
+            ],
+        )
+    }
+
+    /// Deep blue at `min` to shallow blue at `max`.
+    pub fn water(min: f64, max: f64) -> ColourRamp {
+        ColourRamp::from_fractions(min, max, &[(0.0, [20, 45, 110]), (1.0, [70, 130, 200])])
+    }
+
+    pub fn get_colour(&self, value: f64) -> Rgb {
+        let first = self.stops[0];
+        if value <= first.0 {
+            return first.1;
+        }
+        for window in self.stops.windows(2) {
+            let ((from, a), (to, b)) = (window[0], window[1]);
+            if value <= to {
+                let p = (value - from) / (to - from);
+                let mut out = [0; 3];
+                for i in 0..3 {
+                    out[i] = (a[i] as f64 + (b[i] as f64 - a[i] as f64) * p).round() as u8;
+                }
+                return out;
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+/// An RGB image of a mesh, with each cell drawn as a square of `cell_size` pixels.
+pub struct TerrainImage {
+    width: u32,
+    height: u32,
+    cell_size: u32,
+    pixels: Vec<Rgb>,
+}
+
+impl TerrainImage {
+    /// Colours land with `ColourRamp::hypsometric` and water with `ColourRamp::water`, each
+    /// stretched over the heights in the mesh above and below `sea_level`.
+    pub fn new(mesh: &Mesh, sea_level: f64, cell_size: u32) -> TerrainImage {
+        let z = mesh.get_z_vector();
+        let (min, max) = (z.min(), z.max());
+        TerrainImage::with_ramps(
+            mesh,
+            sea_level,
+            &ColourRamp::hypsometric(sea_level, max),
+            &ColourRamp::water(min, sea_level),
+            cell_size,
+        )
+    }
+
+    pub fn with_ramps(
+        mesh: &Mesh,
+        sea_level: f64,
+        land: &ColourRamp,
+        water: &ColourRamp,
+        cell_size: u32,
+    ) -> TerrainImage {
+        assert!(cell_size > 0, "Cell size must be positive");
+        let width = mesh.get_width() as u32 * cell_size;
+        let height = mesh.get_height() as u32 * cell_size;
+        let mut out = TerrainImage {
+            width,
+            height,
+            cell_size,
+            pixels: vec![[0; 3]; (width * height) as usize],
+        };
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                let z = mesh.get_z(x, y);
+                let colour = if z < sea_level {
+                    water.get_colour(z)
+                } else {
+                    land.get_colour(z)
+                };
+                out.fill_cell(x, y, colour);
+            }
+        }
+        out
+    }
+
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Rgb {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, colour: Rgb) {
+        self.pixels[(y * self.width + x) as usize] = colour;
+    }
+
+    fn fill_cell(&mut self, x: i32, y: i32, colour: Rgb) {
+        let (x, y) = (x as u32 * self.cell_size, y as u32 * self.cell_size);
+        for px in x..x + self.cell_size {
+            for py in y..y + self.cell_size {
+                self.set_pixel(px, py, colour);
+            }
+        }
+    }
+
+    /// Shades land at or above `sea_level` as if lit from `azimuth` (radians, clockwise from
+    /// north, which is towards y = 0) at `altitude` (radians above the horizon). Heights are
+    /// multiplied by `exaggeration`, with cells one unit apart. Flat land keeps its colour,
+    /// slopes facing the light are brightened and slopes facing away darkened.
+    pub fn hillshade(
+        &mut self,
+        mesh: &Mesh,
+        sea_level: f64,
+        azimuth: f64,
+        altitude: f64,
+        exaggeration: f64,
+    ) {
+        let light = na::Vector3::new(
+            azimuth.sin() * altitude.cos(),
+            -azimuth.cos() * altitude.cos(),
+            altitude.sin(),
+        );
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                if mesh.get_z(x, y) < sea_level {
+                    continue;
+                }
+                let dx = TerrainImage::get_gradient(mesh, x, y, (1, 0)) * exaggeration;
+                let dy = TerrainImage::get_gradient(mesh, x, y, (0, 1)) * exaggeration;
+                let normal = na::Vector3::new(-dx, -dy, 1.0).normalize();
+                let shade = normal.dot(&light).max(0.0) / light.z;
+                self.shade_cell(x, y, shade);
+            }
+        }
+    }
+
+    // Differences only use cells on the mesh, so edges are not shaded as cliffs down to the out
+    // of bounds height
+    fn get_gradient(mesh: &Mesh, x: i32, y: i32, direction: (i32, i32)) -> f64 {
+        let (dx, dy) = direction;
+        let clamp = |x: i32, y: i32| {
+            (
+                x.max(0).min(mesh.get_width() - 1),
+                y.max(0).min(mesh.get_height() - 1),
+            )
+        };
+        let (x0, y0) = clamp(x - dx, y - dy);
+        let (x1, y1) = clamp(x + dx, y + dy);
+        let distance = ((x1 - x0) + (y1 - y0)) as f64;
+        if distance == 0.0 {
+            0.0
+        } else {
+            (mesh.get_z(x1, y1) - mesh.get_z(x0, y0)) / distance
+        }
+    }
+
+    fn shade_cell(&mut self, x: i32, y: i32, shade: f64) {
+        let (x, y) = (x as u32 * self.cell_size, y as u32 * self.cell_size);
+        for px in x..x + self.cell_size {
+            for py in y..y + self.cell_size {
+                let mut colour = self.get_pixel(px, py);
+                for channel in colour.iter_mut() {
+                    *channel = (*channel as f64 * shade).round().min(255.0) as u8;
+                }
+                self.set_pixel(px, py, colour);
+            }
+        }
+    }
+
+    /// Colours every cell at or above `sea_level` with at least `threshold` flow.
+    pub fn draw_flow(
+        &mut self,
+        mesh: &Mesh,
+        flow_map: &FlowMap,
+        threshold: f64,
+        sea_level: f64,
+        colour: Rgb,
+    ) {
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                if flow_map.get_flow(x, y) >= threshold && mesh.get_z(x, y) >= sea_level {
+                    self.fill_cell(x, y, colour);
+                }
+            }
+        }
+    }
+
+    /// Draws rivers as returned by `river_runner`. Each river runs between the centres of its
+    /// cells, tapering between the widths of the junctions at either end. Widths are in cells.
+    pub fn draw_rivers(&mut self, junctions: &[Node], rivers: &[Edge], colour: Rgb) {
+        let mut widths: HashMap<(usize, usize), f64> = HashMap::new();
+        for junction in junctions {
+            let position = junction.position();
+            let width = junction.width().max(junction.height()) as f64;
+            let entry = widths.entry((position.x, position.y)).or_insert(0.0);
+            *entry = entry.max(width);
+        }
+        let get_width =
+            |position: &na::Vector2<usize>| *widths.get(&(position.x, position.y)).unwrap_or(&0.0);
+        for river in rivers {
+            let (from, to) = (river.from(), river.to());
+            self.draw_segment(
+                (from.x as f64, from.y as f64, get_width(from)),
+                (to.x as f64, to.y as f64, get_width(to)),
+                colour,
+            );
+        }
+    }
+
+    // Ends are (x, y, width) in cells. Pixels are filled if their centre is within half the
+    // interpolated width of the segment, and the segment is always at least one pixel wide.
+    fn draw_segment(&mut self, from: (f64, f64, f64), to: (f64, f64, f64), colour: Rgb) {
+        let cell_size = self.cell_size as f64;
+        let to_pixels = |(x, y, width): (f64, f64, f64)| {
+            (
+                (x + 0.5) * cell_size,
+                (y + 0.5) * cell_size,
+                (width * cell_size / 2.0).max(0.5),
+            )
+        };
+        let (ax, ay, ar) = to_pixels(from);
+        let (bx, by, br) = to_pixels(to);
+        let reach = ar.max(br);
+        let clamp = |value: f64, size: u32| value.max(0.0).min(size as f64) as u32;
+        let (x_min, x_max) = (
+            clamp(ax.min(bx) - reach, self.width),
+            clamp((ax.max(bx) + reach).ceil(), self.width),
+        );
+        let (y_min, y_max) = (
+            clamp(ay.min(by) - reach, self.height),
+            clamp((ay.max(by) + reach).ceil(), self.height),
+        );
+        let length_squared = (bx - ax).powi(2) + (by - ay).powi(2);
+        for px in x_min..x_max {
+            for py in y_min..y_max {
+                let (cx, cy) = (px as f64 + 0.5, py as f64 + 0.5);
+                let t = if length_squared == 0.0 {
+                    0.0
+                } else {
+                    (((cx - ax) * (bx - ax) + (cy - ay) * (by - ay)) / length_squared)
+                        .max(0.0)
+                        .min(1.0)
+                };
+                let (nx, ny) = (ax + (bx - ax) * t, ay + (by - ay) * t);
+                let distance = ((cx - nx).powi(2) + (cy - ny).powi(2)).sqrt();
+                if distance <= ar + (br - ar) * t {
+                    self.set_pixel(px, py, colour);
+                }
+            }
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), EncodingError> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in self.pixels.iter() {
+            data.extend_from_slice(pixel);
+        }
+        let mut encoder = Encoder::new(writer, self.width, self.height);
+        encoder.set(ColorType::RGB).set(BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&data)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EncodingError> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use png::{Decoder, Transformations};
+
+    const RED: Rgb = [255, 0, 0];
+    const GREY: Rgb = [100, 100, 100];
+    const BLUE: Rgb = [0, 0, 255];
+
+    fn ramps() -> (ColourRamp, ColourRamp) {
+        (
+            ColourRamp::new(vec![(0.0, GREY)]),
+            ColourRamp::new(vec![(0.0, BLUE)]),
+        )
+    }
+
+    #[test]
+    fn test_colour_ramp() {
+        let ramp = ColourRamp::new(vec![
+            (0.0, [0, 0, 0]),
+            (1.0, [100, 200, 0]),
+            (3.0, [100, 0, 40]),
+        ]);
+
+        assert_eq!(ramp.get_colour(-1.0), [0, 0, 0]);
+        assert_eq!(ramp.get_colour(0.5), [50, 100, 0]);
+        assert_eq!(ramp.get_colour(2.0), [100, 100, 20]);
+        assert_eq!(ramp.get_colour(4.0), [100, 0, 40]);
+    }
+
+    #[test]
+    fn test_new() {
+        let mut mesh = Mesh::with_size(2, 1, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 1, &[-1.0, 2.0]));
+
+        let image = TerrainImage::new(&mesh, 0.0, 2);
+
+        assert_eq!((image.get_width(), image.get_height()), (4, 2));
+        assert_eq!(
+            image.get_pixel(0, 0),
+            ColourRamp::water(-1.0, 0.0).get_colour(-1.0)
+        );
+        assert_eq!(image.get_pixel(1, 1), image.get_pixel(0, 0));
+        assert_eq!(image.get_pixel(3, 1), [255, 255, 255]);
+    }
+
+    #[test]
+    fn hillshade_should_brighten_slopes_facing_light() {
+        let mut mesh = Mesh::with_size(3, 3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+        ));
+        let (land, water) = ramps();
+        let mut image = TerrainImage::with_ramps(&mesh, 0.0, &land, &water, 1);
+
+        // Lit from the east, which is downhill
+        image.hillshade(&mesh, 0.0, std::f64::consts::FRAC_PI_2, 0.5, 1.0);
+
+        assert_eq!(image.get_pixel(0, 1), GREY);
+        assert!(image.get_pixel(1, 1)[0] > GREY[0]);
+
+        image.hillshade(&mesh, 0.0, -std::f64::consts::FRAC_PI_2, 0.5, 1.0);
+
+        assert!(image.get_pixel(1, 1)[0] < GREY[0]);
+    }
+
+    #[test]
+    fn hillshade_should_not_shade_flat_edges() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::repeat(3, 3, 1.0));
+        let (land, water) = ramps();
+        let mut image = TerrainImage::with_ramps(&mesh, 0.0, &land, &water, 1);
+
+        image.hillshade(&mesh, 0.0, 0.0, 0.5, 10.0);
+
+        for x in 0..3 {
+            for y in 0..3 {
+                assert_eq!(image.get_pixel(x, y), GREY);
+            }
+        }
+    }
+
+    #[test]
+    fn hillshade_should_not_shade_water() {
+        let mut mesh = Mesh::with_size(2, 1, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 1, &[-1.0, 1.0]));
+        let (land, water) = ramps();
+        let mut image = TerrainImage::with_ramps(&mesh, 0.0, &land, &water, 1);
+
+        image.hillshade(&mesh, 0.0, 0.0, 0.5, 1.0);
+
+        assert_eq!(image.get_pixel(0, 0), BLUE);
+    }
+
+    #[test]
+    fn test_draw_flow() {
+        let mut mesh = Mesh::with_size(3, 1, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(3, 1, &[1.0, 1.0, -1.0]));
+        let mut flow_map = FlowMap::with_size(3, 1);
+        flow_map.set_flow(na::DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 3.0]));
+        let (land, water) = ramps();
+        let mut image = TerrainImage::with_ramps(&mesh, 0.0, &land, &water, 1);
+
+        image.draw_flow(&mesh, &flow_map, 2.0, 0.0, RED);
+
+        assert_eq!(image.get_pixel(0, 0), GREY);
+        assert_eq!(image.get_pixel(1, 0), RED);
+        assert_eq!(image.get_pixel(2, 0), BLUE);
+    }
+
+    #[test]
+    fn test_draw_rivers() {
+        let mesh = Mesh::with_size(3, 2, 0.0);
+        let (land, water) = ramps();
+        let mut image = TerrainImage::with_ramps(&mesh, -1.0, &land, &water, 4);
+        let from = na::Vector2::new(0, 0);
+        let to = na::Vector2::new(2, 0);
+        let junctions = vec![Node::new(from, 0.0, 0.5), Node::new(to, 0.0, 1.0)];
+
+        image.draw_rivers(&junctions, &[Edge::new(from, to)], RED);
+
+        assert_eq!(image.get_pixel(2, 2), RED);
+        assert_eq!(image.get_pixel(2, 0), GREY);
+        assert_eq!(image.get_pixel(10, 0), RED);
+        assert_eq!(image.get_pixel(10, 4), GREY);
+        assert_eq!(image.get_pixel(0, 2), GREY);
+    }
+
+    #[test]
+    fn test_write() {
+        let mesh = Mesh::with_size(3, 2, 0.0);
+        let (land, water) = ramps();
+        let image = TerrainImage::with_ramps(&mesh, -1.0, &land, &water, 2);
+        let mut png = vec![];
+
+        image.write(&mut png).unwrap();
+
+        let mut decoder = Decoder::new(&png[..]);
+        decoder.set(Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (6, 4));
+        assert_eq!(info.color_type, ColorType::RGB);
+        assert_eq!(&data[0..3], &GREY);
+    }
+
+}
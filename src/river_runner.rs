@@ -1,12 +1,15 @@
-use downhill_map::DownhillMap;
-use downhill_map::DIRECTIONS;
+use downhill_map::ALL_DIRECTIONS;
+use downhill_map::{DownhillMap, Neighbourhood};
 use flow_map::FlowMap;
 use isometric::terrain::{Edge, Node};
 use isometric::Color;
+use lakes::{LakeDownhillMap, LakeMap};
 use mesh::Mesh;
 use rand::prelude::*;
 use scale::Scale;
-use single_downhill_map::{RandomDownhillMap, SingleDownhillMap};
+use single_downhill_map::{
+    RandomDownhillMap, SingleDownhillMap, SteepestDownhillMap, WeightedDownhillMap,
+};
 
 pub fn get_junctions_and_rivers<R: Rng>(
     mesh: &Mesh,
@@ -15,7 +18,25 @@ pub fn get_junctions_and_rivers<R: Rng>(
     flow_to_width: (f64, f64),
     rng: &mut Box<R>,
 ) -> (Vec<Node>, Vec<Edge>) {
-    let downhill_map = DownhillMap::new(&mesh);
+    get_junctions_and_rivers_with_neighbourhood(
+        mesh,
+        threshold as f64,
+        sea_level,
+        flow_to_width,
+        Neighbourhood::Four,
+        rng,
+    )
+}
+
+pub fn get_junctions_and_rivers_with_neighbourhood<R: Rng>(
+    mesh: &Mesh,
+    threshold: f64,
+    sea_level: f64,
+    flow_to_width: (f64, f64),
+    neighbourhood: Neighbourhood,
+    rng: &mut Box<R>,
+) -> (Vec<Node>, Vec<Edge>) {
+    let downhill_map = DownhillMap::with_neighbourhood(&mesh, neighbourhood);
     let random_downhill_map: Box<SingleDownhillMap> =
         Box::new(RandomDownhillMap::new(&downhill_map, rng));
 
@@ -25,17 +46,115 @@ pub fn get_junctions_and_rivers<R: Rng>(
         sea_level,
         flow_to_width,
         &random_downhill_map,
+        None,
+    )
+}
+
+pub fn get_junctions_and_rivers_weighted<R: Rng>(
+    mesh: &Mesh,
+    threshold: f64,
+    sea_level: f64,
+    flow_to_width: (f64, f64),
+    neighbourhood: Neighbourhood,
+    exponent: f64,
+    rng: &mut Box<R>,
+) -> (Vec<Node>, Vec<Edge>) {
+    let downhill_map = DownhillMap::with_neighbourhood(&mesh, neighbourhood);
+    let weighted_downhill_map: Box<SingleDownhillMap> = Box::new(WeightedDownhillMap::new(
+        &mesh,
+        &downhill_map,
+        exponent,
+        rng,
+    ));
+
+    get_junctions_and_rivers_from_downhill_map(
+        &mesh,
+        threshold,
+        sea_level,
+        flow_to_width,
+        &weighted_downhill_map,
+        None,
+    )
+}
+
+pub fn get_junctions_and_rivers_steepest(
+    mesh: &Mesh,
+    threshold: f64,
+    sea_level: f64,
+    flow_to_width: (f64, f64),
+    neighbourhood: Neighbourhood,
+) -> (Vec<Node>, Vec<Edge>) {
+    let downhill_map = DownhillMap::with_neighbourhood(&mesh, neighbourhood);
+    let steepest_downhill_map: Box<SingleDownhillMap> =
+        Box::new(SteepestDownhillMap::new(&mesh, &downhill_map));
+
+    get_junctions_and_rivers_from_downhill_map(
+        &mesh,
+        threshold,
+        sea_level,
+        flow_to_width,
+        &steepest_downhill_map,
+        None,
+    )
+}
+
+pub fn get_junctions_and_rivers_with_precipitation<R: Rng>(
+    mesh: &Mesh,
+    precipitation: &na::DMatrix<f64>,
+    threshold: f64,
+    sea_level: f64,
+    flow_to_width: (f64, f64),
+    neighbourhood: Neighbourhood,
+    rng: &mut Box<R>,
+) -> (Vec<Node>, Vec<Edge>) {
+    let downhill_map = DownhillMap::with_neighbourhood(&mesh, neighbourhood);
+    let random_downhill_map: Box<SingleDownhillMap> =
+        Box::new(RandomDownhillMap::new(&downhill_map, rng));
+    let flow_map = FlowMap::from_precipitation(&mesh, &random_downhill_map, precipitation);
+
+    get_junctions_and_rivers_from_flow_map(
+        &mesh,
+        threshold,
+        sea_level,
+        flow_to_width,
+        &random_downhill_map,
+        &flow_map,
+        None,
+    )
+}
+
+pub fn get_junctions_and_rivers_through_lakes<R: Rng>(
+    mesh: &Mesh,
+    lake_map: &LakeMap,
+    threshold: f64,
+    sea_level: f64,
+    flow_to_width: (f64, f64),
+    neighbourhood: Neighbourhood,
+    rng: &mut Box<R>,
+) -> (Vec<Node>, Vec<Edge>) {
+    let downhill_map = DownhillMap::with_neighbourhood(&mesh, neighbourhood);
+    let lake_downhill_map: Box<SingleDownhillMap> =
+        Box::new(LakeDownhillMap::new(&downhill_map, lake_map, rng));
+
+    get_junctions_and_rivers_from_downhill_map(
+        &mesh,
+        threshold,
+        sea_level,
+        flow_to_width,
+        &lake_downhill_map,
+        Some(lake_map),
     )
 }
 
 fn get_junctions_and_rivers_from_downhill_map(
     mesh: &Mesh,
-    threshold: u32,
+    threshold: f64,
     sea_level: f64,
     flow_to_width: (f64, f64),
     downhill_map: &Box<SingleDownhillMap>,
+    lake_map: Option<&LakeMap>,
 ) -> (Vec<Node>, Vec<Edge>) {
-    let flow_map = FlowMap::from(&mesh, &downhill_map);
+    let flow_map = FlowMap::from_topological(&mesh, &downhill_map);
     get_junctions_and_rivers_from_flow_map(
         &mesh,
         threshold,
@@ -43,6 +162,7 @@ fn get_junctions_and_rivers_from_downhill_map(
         flow_to_width,
         &downhill_map,
         &flow_map,
+        lake_map,
     )
 }
 
@@ -51,20 +171,50 @@ fn get_neighbour(
     mesh: &Mesh,
     downhill_map: &Box<SingleDownhillMap>,
 ) -> Option<na::Vector2<usize>> {
-    let direction = DIRECTIONS[downhill_map.get_direction(position.x as i32, position.y as i32)];
-    let nx = (position.x as i32) + direction.0;
-    let ny = (position.y as i32) + direction.1;
-    if mesh.in_bounds(nx, ny) {
-        Some(na::Vector2::new(nx as usize, ny as usize))
+    let direction =
+        ALL_DIRECTIONS[downhill_map.get_direction(position.x as i32, position.y as i32)];
+    let (x, y) = (position.x as i32, position.y as i32);
+    match mesh.get_neighbour(x, y, direction) {
+        // Rivers that cross a wrapped edge are not drawn across the mesh
+        Some((nx, ny)) if (nx - x).abs() <= 1 && (ny - y).abs() <= 1 => {
+            Some(na::Vector2::new(nx as usize, ny as usize))
+        }
+        _ => None,
+    }
+}
+
+// Edges must be axis aligned, so diagonal segments are drawn through the lower of the two cells
+// that share an edge with both ends
+fn get_corner(from: na::Vector2<usize>, to: na::Vector2<usize>, mesh: &Mesh) -> na::Vector2<usize> {
+    let a = na::Vector2::new(to.x, from.y);
+    let b = na::Vector2::new(from.x, to.y);
+    if mesh.get_z(a.x as i32, a.y as i32) <= mesh.get_z(b.x as i32, b.y as i32) {
+        a
+    } else {
+        b
+    }
+}
+
+fn push_segment(
+    junctions: &mut Vec<Node>,
+    rivers: &mut Vec<Edge>,
+    from: (na::Vector2<usize>, f32),
+    to: (na::Vector2<usize>, f32),
+) {
+    if from.0.x == to.0.x {
+        junctions.push(Node::new(from.0, from.1, 0.0));
+        junctions.push(Node::new(to.0, to.1, 0.0));
     } else {
-        None
+        junctions.push(Node::new(from.0, 0.0, from.1));
+        junctions.push(Node::new(to.0, 0.0, to.1));
     }
+    rivers.push(Edge::new(from.0, to.0));
 }
 
-fn get_max_flow_over_sea_level(mesh: &Mesh, sea_level: f64, flow_map: &FlowMap) -> u32 {
-    let mut out = 0;
+fn get_max_flow_over_sea_level(mesh: &Mesh, sea_level: f64, flow_map: &FlowMap) -> f64 {
+    let mut out: f64 = 0.0;
     for x in 0..mesh.get_width() {
-        for y in 0..mesh.get_width() {
+        for y in 0..mesh.get_height() {
             if mesh.get_z(x, y) >= sea_level {
                 out = out.max(flow_map.get_flow(x, y));
             }
@@ -73,38 +223,54 @@ fn get_max_flow_over_sea_level(mesh: &Mesh, sea_level: f64, flow_map: &FlowMap)
     out
 }
 
-fn get_junctions_and_rivers_from_flow_map(
+pub fn get_junctions_and_rivers_from_flow_map(
     mesh: &Mesh,
-    threshold: u32,
+    threshold: f64,
     sea_level: f64,
     flow_to_width: (f64, f64),
     downhill_map: &Box<SingleDownhillMap>,
     flow_map: &FlowMap,
+    lake_map: Option<&LakeMap>,
 ) -> (Vec<Node>, Vec<Edge>) {
     let mut junctions = vec![];
     let mut rivers = vec![];
 
-    let max_flow_over_sea_level = get_max_flow_over_sea_level(mesh, sea_level, flow_map) as f64;
-    let flow_scale = Scale::new((threshold as f64, max_flow_over_sea_level), flow_to_width);
+    let max_flow_over_sea_level = get_max_flow_over_sea_level(mesh, sea_level, flow_map);
+    let flow_scale = Scale::new((threshold, max_flow_over_sea_level), flow_to_width);
 
     for x in 0..mesh.get_width() {
-        for y in 0..mesh.get_width() {
+        for y in 0..mesh.get_height() {
             let flow = flow_map.get_flow(x, y);
-            if flow >= threshold && mesh.get_z(x, y) >= sea_level {
+            let in_lake = lake_map.map_or(false, |lake_map| lake_map.is_lake(x, y));
+            if flow >= threshold && mesh.get_z(x, y) >= sea_level && !in_lake {
                 let position = na::Vector2::new(x as usize, y as usize);
                 if let Some(neighbour) = get_neighbour(position, mesh, downhill_map) {
                     let neighbour_flow = flow_map.get_flow(neighbour.x as i32, neighbour.y as i32);
-                    let from_width = flow_scale.scale(flow as f64) as f32;
-                    let to_width = flow_scale.scale(neighbour_flow as f64) as f32;
-                    if position.x == neighbour.x {
-                        junctions.push(Node::new(position, from_width, 0.0));
-                        junctions.push(Node::new(neighbour, to_width, 0.0));
+                    let from_width = flow_scale.scale(flow) as f32;
+                    let to_width = flow_scale.scale(neighbour_flow) as f32;
+                    if position.x == neighbour.x || position.y == neighbour.y {
+                        push_segment(
+                            &mut junctions,
+                            &mut rivers,
+                            (position, from_width),
+                            (neighbour, to_width),
+                        );
                     } else {
-                        junctions.push(Node::new(position, 0.0, from_width));
-                        junctions.push(Node::new(neighbour, 0.0, to_width));
+                        let corner = get_corner(position, neighbour, mesh);
+                        let corner_width = (from_width + to_width) / 2.0;
+                        push_segment(
+                            &mut junctions,
+                            &mut rivers,
+                            (position, from_width),
+                            (corner, corner_width),
+                        );
+                        push_segment(
+                            &mut junctions,
+                            &mut rivers,
+                            (corner, corner_width),
+                            (neighbour, to_width),
+                        );
                     }
-
-                    rivers.push(Edge::new(position, neighbour));
                 }
             }
         }
@@ -148,7 +314,9 @@ mod tests {
         flow_map.set_flow(na::DMatrix::from_row_slice(
             4,
             4,
-            &[1, 2, 5, 7, 3, 7, 9, 12, 2, 2, 2, 2, 1, 1, 1, 1],
+            &[
+                1.0, 2.0, 5.0, 7.0, 3.0, 7.0, 9.0, 12.0, 2.0, 2.0, 2.0, 2.0, 1.0, 1.0, 1.0, 1.0,
+            ],
         ));
         flow_map
     }
@@ -168,20 +336,200 @@ mod tests {
         assert_eq!(get_neighbour(position, &mesh(), &downhill_map()), None);
     }
 
+    #[test]
+    fn test_get_downhill_diagonal() {
+        let downhill_map: Box<SingleDownhillMap> =
+            Box::new(MockDownhillMap::new(vec![vec![6; 4]; 4]));
+        let position = na::Vector2::new(1, 2);
+        assert_eq!(
+            get_neighbour(position, &mesh(), &downhill_map),
+            Some(na::Vector2::new(2, 3))
+        );
+    }
+
+    #[test]
+    fn test_get_corner() {
+        assert_eq!(
+            get_corner(na::Vector2::new(0, 1), na::Vector2::new(1, 2), &mesh()),
+            na::Vector2::new(1, 1)
+        );
+        assert_eq!(
+            get_corner(na::Vector2::new(2, 2), na::Vector2::new(1, 3), &mesh()),
+            na::Vector2::new(1, 2)
+        );
+    }
+
+    #[test]
+    fn diagonal_rivers_should_be_split_into_axis_aligned_edges() {
+        let downhill_map: Box<SingleDownhillMap> =
+            Box::new(MockDownhillMap::new(vec![vec![6; 4]; 4]));
+        let mut flow_map = FlowMap::new(4);
+        flow_map.set_flow(na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 3.0, 1.0, 1.0,
+            ],
+        ));
+
+        let (junctions, rivers) = get_junctions_and_rivers_from_flow_map(
+            &mesh(),
+            2.0,
+            0.5,
+            (0.0, 1.0),
+            &downhill_map,
+            &flow_map,
+            None,
+        );
+
+        assert_eq!(rivers.len(), 0);
+        assert_eq!(junctions.len(), 0);
+
+        flow_map.set_flow(na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                3.0, 1.0, 1.0, 1.0, 1.0, 3.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
+            ],
+        ));
+
+        let (junctions, rivers) = get_junctions_and_rivers_from_flow_map(
+            &mesh(),
+            2.0,
+            0.5,
+            (0.0, 1.0),
+            &downhill_map,
+            &flow_map,
+            None,
+        );
+
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(0, 0), na::Vector2::new(1, 0))));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(1, 0), na::Vector2::new(1, 1))));
+        assert!(junctions.contains(&Node::new(na::Vector2::new(1, 0), 0.0, 1.0)));
+        assert!(junctions.contains(&Node::new(na::Vector2::new(1, 0), 1.0, 0.0)));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(1, 1), na::Vector2::new(1, 2))));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(1, 2), na::Vector2::new(2, 2))));
+        assert_eq!(rivers.len(), 4);
+    }
+
     #[test]
     fn test_get_max_flow_over_sea_level() {
-        assert_eq!(get_max_flow_over_sea_level(&mesh(), 0.5, &flow_map()), 7);
+        assert_eq!(get_max_flow_over_sea_level(&mesh(), 0.5, &flow_map()), 7.0);
+    }
+
+    #[test]
+    fn steepest_rivers_should_be_reproducible() {
+        use mesh_splitter::MeshSplitter;
+
+        let mut rng = Box::new(rand::thread_rng());
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        let mesh = MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 5);
+
+        let first =
+            get_junctions_and_rivers_steepest(&mesh, 4.0, 0.0, (0.0, 1.0), Neighbourhood::Eight);
+        let second =
+            get_junctions_and_rivers_steepest(&mesh, 4.0, 0.0, (0.0, 1.0), Neighbourhood::Eight);
+
+        assert!(!first.1.is_empty());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn river_width_should_follow_precipitation() {
+        let precipitation = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1.0, 0.0, 0.0, 0.0, 4.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+            ],
+        );
+        let flow_map = FlowMap::from_precipitation(&mesh(), &downhill_map(), &precipitation);
+
+        let (junctions, rivers) = get_junctions_and_rivers_from_flow_map(
+            &mesh(),
+            1.0,
+            0.5,
+            (0.0, 1.0),
+            &downhill_map(),
+            &flow_map,
+            None,
+        );
+
+        assert!(junctions.contains(&Node::new(na::Vector2::new(1, 0), 1.0, 0.0)));
+        assert!(junctions.contains(&Node::new(na::Vector2::new(1, 2), 1.0, 0.0)));
+        assert!(junctions.contains(&Node::new(na::Vector2::new(0, 0), 0.0, 0.0)));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(1, 1), na::Vector2::new(1, 2))));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(0, 2), na::Vector2::new(0, 3))));
+        assert_eq!(rivers.len(), 5);
+    }
+
+    #[test]
+    fn rivers_should_not_start_in_lakes() {
+        let mut mesh = Mesh::new(4, 0.0);
+        let z = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.5, 0.8, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
+            ],
+        );
+        mesh.set_z_vector(z);
+        let lake_map = LakeMap::new(&mesh);
+
+        let (_, rivers) = get_junctions_and_rivers_from_flow_map(
+            &mesh,
+            3.0,
+            0.5,
+            (0.0, 1.0),
+            &downhill_map(),
+            &flow_map(),
+            Some(&lake_map),
+        );
+
+        assert!(!rivers.contains(&Edge::new(na::Vector2::new(1, 2), na::Vector2::new(1, 3))));
+        assert_eq!(rivers.len(), 3);
+    }
+
+    #[test]
+    fn rivers_should_continue_past_lakes() {
+        let mut mesh = Mesh::new(4, 0.0);
+        let z = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                3.0, 3.0, 3.0, 3.0, 3.0, 1.0, 2.0, 3.0, 3.0, 2.0, 2.0, 3.0, 3.0, 1.5, 3.0, 3.0,
+            ],
+        );
+        mesh.set_z_vector(z);
+        let lake_map = LakeMap::new(&mesh);
+        let mut rng = Box::new(rand::thread_rng());
+
+        let (_, rivers) = get_junctions_and_rivers_through_lakes(
+            &mesh,
+            &lake_map,
+            4.0,
+            0.0,
+            (0.0, 1.0),
+            Neighbourhood::Four,
+            &mut rng,
+        );
+
+        assert_eq!(lake_map.get_lakes().len(), 1);
+        assert_eq!(lake_map.get_lake(0).get_outlet(), Some((2, 1)));
+        assert!(rivers.contains(&Edge::new(na::Vector2::new(2, 1), na::Vector2::new(3, 1))));
     }
 
     #[test]
     fn test_get_junctions_and_rivers_from_flow_map() {
         let (junctions, rivers) = get_junctions_and_rivers_from_flow_map(
             &mesh(),
-            3,
+            3.0,
             0.5,
             (0.0, 1.0),
             &downhill_map(),
             &flow_map(),
+            None,
         );
 
         println!("{:?}", rivers);
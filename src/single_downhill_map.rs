@@ -1,4 +1,5 @@
-use downhill_map::DownhillMap;
+use downhill_map::{get_slope, DownhillMap};
+use mesh::Mesh;
 use rand::prelude::*;
 
 pub trait SingleDownhillMap {
@@ -21,8 +22,11 @@ impl SingleDownhillMap for MockDownhillMap {
     }
 }
 
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RandomDownhillMap {
     width: i32,
+    height: i32,
     directions: na::DMatrix<u8>,
 }
 
@@ -31,30 +35,146 @@ impl RandomDownhillMap {
         if !downhill_map.all_cells_have_downhill() {
             panic!("Not all cells have downhill");
         }
-        let width = downhill_map.get_width();
-        let mut directions = na::DMatrix::zeros(width as usize, width as usize);
+        let (width, height) = (downhill_map.get_width(), downhill_map.get_height());
+        let mut directions = na::DMatrix::zeros(width as usize, height as usize);
         for x in 0..width {
-            for y in 0..width {
-                let candidates: Vec<u8> = downhill_map
-                    .get_directions(x, y)
-                    .iter()
-                    .enumerate()
-                    .filter(|(_, downhill)| **downhill)
-                    .map(|(index, _)| index as u8)
+            for y in 0..height {
+                let candidates = downhill_map.get_candidates(x, y);
+                directions[(x as usize, y as usize)] = *candidates.choose(&mut *rng).unwrap() as u8;
+            }
+        }
+        RandomDownhillMap {
+            width,
+            height,
+            directions,
+        }
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+}
+
+impl SingleDownhillMap for RandomDownhillMap {
+    fn get_direction(&self, x: i32, y: i32) -> usize {
+        self.directions[(x as usize, y as usize)] as usize
+    }
+}
+
+pub struct SteepestDownhillMap {
+    width: i32,
+    height: i32,
+    directions: na::DMatrix<u8>,
+}
+
+impl SteepestDownhillMap {
+    pub fn new(mesh: &Mesh, downhill_map: &DownhillMap) -> SteepestDownhillMap {
+        if !downhill_map.all_cells_have_downhill() {
+            panic!("Not all cells have downhill");
+        }
+        let (width, height) = (downhill_map.get_width(), downhill_map.get_height());
+        let neighbourhood = downhill_map.get_neighbourhood().get_directions();
+        let mut directions = na::DMatrix::zeros(width as usize, height as usize);
+        for x in 0..width {
+            for y in 0..height {
+                let mut steepest = None;
+                let mut steepest_slope = 0.0;
+                // Ties go to the lowest direction index
+                for candidate in downhill_map.get_candidates(x, y) {
+                    let slope = get_slope(mesh, x, y, neighbourhood[candidate]);
+                    if steepest.is_none() || slope > steepest_slope {
+                        steepest = Some(candidate);
+                        steepest_slope = slope;
+                    }
+                }
+                directions[(x as usize, y as usize)] = steepest.unwrap() as u8;
+            }
+        }
+        SteepestDownhillMap {
+            width,
+            height,
+            directions,
+        }
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+}
+
+impl SingleDownhillMap for SteepestDownhillMap {
+    fn get_direction(&self, x: i32, y: i32) -> usize {
+        self.directions[(x as usize, y as usize)] as usize
+    }
+}
+
+pub struct WeightedDownhillMap {
+    width: i32,
+    height: i32,
+    directions: na::DMatrix<u8>,
+}
+
+impl WeightedDownhillMap {
+    /// Chooses randomly among downhill directions, weighted by slope raised to `exponent`. An
+    /// exponent of zero weights all downhill directions equally, higher exponents approach
+    /// steepest descent.
+    pub fn new<R: Rng>(
+        mesh: &Mesh,
+        downhill_map: &DownhillMap,
+        exponent: f64,
+        rng: &mut Box<R>,
+    ) -> WeightedDownhillMap {
+        if !downhill_map.all_cells_have_downhill() {
+            panic!("Not all cells have downhill");
+        }
+        let (width, height) = (downhill_map.get_width(), downhill_map.get_height());
+        let neighbourhood = downhill_map.get_neighbourhood().get_directions();
+        let mut directions = na::DMatrix::zeros(width as usize, height as usize);
+        for x in 0..width {
+            for y in 0..height {
+                let slopes: Vec<(usize, f64)> = downhill_map
+                    .get_candidates(x, y)
+                    .into_iter()
+                    .map(|candidate| (candidate, get_slope(mesh, x, y, neighbourhood[candidate])))
+                    .collect();
+                // Relative to the steepest slope so that the weights cannot all underflow to 0
+                let max_slope = slopes.iter().map(|slope| slope.1).fold(0.0, f64::max);
+                let candidates: Vec<(usize, f64)> = slopes
+                    .into_iter()
+                    .map(|(candidate, slope)| (candidate, (slope / max_slope).powf(exponent)))
                     .collect();
 
-                directions[(x as usize, y as usize)] = *candidates.choose(&mut *rng).unwrap();
+                let choice = candidates
+                    .choose_weighted(&mut **rng, |candidate| candidate.1)
+                    .unwrap();
+                directions[(x as usize, y as usize)] = choice.0 as u8;
             }
         }
-        RandomDownhillMap { width, directions }
+        WeightedDownhillMap {
+            width,
+            height,
+            directions,
+        }
     }
 
     pub fn get_width(&self) -> i32 {
         self.width
     }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
 }
 
-impl SingleDownhillMap for RandomDownhillMap {
+impl SingleDownhillMap for WeightedDownhillMap {
     fn get_direction(&self, x: i32, y: i32) -> usize {
         self.directions[(x as usize, y as usize)] as usize
     }
@@ -64,7 +184,7 @@ impl SingleDownhillMap for RandomDownhillMap {
 mod tests {
 
     use super::*;
-    use mesh::Mesh;
+    use rand::rngs::StdRng;
 
     #[test]
     fn random_downhill_map_should_contain_downhill_directions() {
@@ -84,7 +204,7 @@ mod tests {
         let random_downhill_map = RandomDownhillMap::new(&downhill_map, &mut rng);
 
         for x in 0..random_downhill_map.get_width() {
-            for y in 0..random_downhill_map.get_width() {
+            for y in 0..random_downhill_map.get_height() {
                 let direction = random_downhill_map.get_direction(x, y);
                 let downhill = &downhill_map.get_directions(x, y)[direction];
                 assert_eq!(*downhill, true);
@@ -92,4 +212,120 @@ mod tests {
         }
     }
 
+    #[test]
+    fn random_downhill_map_should_use_diagonals_with_eight_neighbourhood() {
+        use downhill_map::Neighbourhood;
+
+        let mut mesh = Mesh::new(3, 0.0);
+        let z = na::DMatrix::from_row_slice(3, 3, &[0.1, 0.8, 0.8, 0.8, 0.5, 0.8, 0.8, 0.8, 0.8]);
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let random_downhill_map = RandomDownhillMap::new(&downhill_map, &mut rng);
+
+        assert_eq!(random_downhill_map.get_direction(1, 1), 4);
+    }
+
+    #[test]
+    fn weighted_downhill_map_should_contain_downhill_directions() {
+        let mut mesh = Mesh::new(4, 0.0);
+        let z = na::DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                0.3, 0.8, 0.7, 0.6, 0.4, 0.9, 0.4, 0.5, 0.5, 0.8, 0.3, 0.2, 0.6, 0.7, 0.6, 0.1,
+            ],
+        );
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::new(&mesh);
+
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let weighted_downhill_map = WeightedDownhillMap::new(&mesh, &downhill_map, 1.0, &mut rng);
+
+        for x in 0..weighted_downhill_map.get_width() {
+            for y in 0..weighted_downhill_map.get_height() {
+                let direction = weighted_downhill_map.get_direction(x, y);
+                let downhill = &downhill_map.get_directions(x, y)[direction];
+                assert_eq!(*downhill, true);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_downhill_map_should_favour_steep_directions_with_high_exponent() {
+        let mut mesh = Mesh::new(3, 0.0);
+        let z = na::DMatrix::from_row_slice(3, 3, &[0.8, 0.8, 0.8, 0.85, 0.9, 0.8, 0.8, 0.1, 0.8]);
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::new(&mesh);
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+
+        for _ in 0..100 {
+            let weighted_downhill_map =
+                WeightedDownhillMap::new(&mesh, &downhill_map, 16.0, &mut rng);
+            assert_eq!(weighted_downhill_map.get_direction(1, 1), 2);
+        }
+    }
+
+    #[test]
+    fn weighted_downhill_map_should_handle_gentle_slopes_with_high_exponent() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_element(3, 3, 0.5));
+        mesh.set_z(1, 1, 0.5 + 1e-6);
+
+        let downhill_map = DownhillMap::new(&mesh);
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let weighted_downhill_map = WeightedDownhillMap::new(&mesh, &downhill_map, 64.0, &mut rng);
+
+        let direction = weighted_downhill_map.get_direction(1, 1);
+        assert_eq!(downhill_map.get_directions(1, 1)[direction], true);
+    }
+
+    #[test]
+    fn steepest_downhill_map_should_pick_greatest_drop() {
+        let mut mesh = Mesh::new(3, 0.0);
+        let z = na::DMatrix::from_row_slice(3, 3, &[0.1, 0.8, 0.8, 0.4, 0.9, 0.8, 0.8, 0.5, 0.8]);
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::new(&mesh);
+        let steepest_downhill_map = SteepestDownhillMap::new(&mesh, &downhill_map);
+
+        assert_eq!(steepest_downhill_map.get_direction(1, 1), 1);
+        assert_eq!(steepest_downhill_map.get_direction(0, 0), 0);
+    }
+
+    #[test]
+    fn steepest_downhill_map_should_account_for_diagonal_distance() {
+        use downhill_map::Neighbourhood;
+
+        let mut mesh = Mesh::new(3, 0.0);
+        let z = na::DMatrix::from_row_slice(3, 3, &[0.1, 0.8, 0.8, 0.8, 1.0, 0.8, 0.8, 0.8, 0.8]);
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+        let steepest_downhill_map = SteepestDownhillMap::new(&mesh, &downhill_map);
+
+        assert_eq!(steepest_downhill_map.get_direction(1, 1), 4);
+
+        mesh.set_z(0, 0, 0.75);
+        let downhill_map = DownhillMap::with_neighbourhood(&mesh, Neighbourhood::Eight);
+        let steepest_downhill_map = SteepestDownhillMap::new(&mesh, &downhill_map);
+
+        assert_eq!(steepest_downhill_map.get_direction(1, 1), 0);
+    }
+
+    #[test]
+    fn steepest_downhill_map_should_break_ties_by_direction() {
+        let mut mesh = Mesh::new(3, 0.0);
+        let z = na::DMatrix::from_row_slice(3, 3, &[0.8, 0.5, 0.8, 0.8, 0.9, 0.5, 0.8, 0.8, 0.8]);
+        mesh.set_z_vector(z);
+
+        let downhill_map = DownhillMap::new(&mesh);
+        let steepest_downhill_map = SteepestDownhillMap::new(&mesh, &downhill_map);
+
+        assert_eq!(steepest_downhill_map.get_direction(1, 1), 0);
+    }
 }
@@ -0,0 +1,201 @@
+use downhill_map::{get_slope, DownhillMap, Neighbourhood};
+use erosion::ErosionResult;
+use flow_map::FlowMap;
+use mesh::Mesh;
+use single_downhill_map::{SingleDownhillMap, SteepestDownhillMap};
+
+// Smallest drop left between a cell and the cell it drains to, so that every cell keeps a
+// strictly downhill neighbour
+const MIN_DROP: f64 = 1e-9;
+
+pub struct StreamPowerErosion {
+    erodibility: f64,
+    area_exponent: f64,
+    slope_exponent: f64,
+    uplift: f64,
+    time_step: f64,
+    neighbourhood: Neighbourhood,
+}
+
+impl StreamPowerErosion {
+    /// Each time step every cell rises by `uplift * time_step` and is lowered by
+    /// `erodibility * area^area_exponent * slope^slope_exponent * time_step`, where area is the
+    /// number of cells draining through it and slope is towards the steepest downhill neighbour.
+    pub fn new(
+        erodibility: f64,
+        area_exponent: f64,
+        slope_exponent: f64,
+        uplift: f64,
+        time_step: f64,
+        neighbourhood: Neighbourhood,
+    ) -> StreamPowerErosion {
+        StreamPowerErosion {
+            erodibility,
+            area_exponent,
+            slope_exponent,
+            uplift,
+            time_step,
+            neighbourhood,
+        }
+    }
+
+    /// The mesh must have the downhill property (see `DownhillMap::all_cells_have_downhill`),
+    /// which is preserved after every step.
+    /// Uplift, and raising a cell to keep it above the cell it drains to, are not counted as
+    /// deposition in the result.
+    pub fn erode(&self, mesh: Mesh, steps: usize) -> ErosionResult {
+        let mut result = ErosionResult::new(mesh);
+        for _ in 0..steps {
+            self.step(&mut result);
+        }
+        result
+    }
+
+    fn get_cells_lowest_first(mesh: &Mesh) -> Vec<(i32, i32)> {
+        let mut out = Vec::with_capacity(mesh.get_cell_count());
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                out.push((x, y));
+            }
+        }
+        out.sort_by(|a, b| {
+            mesh.get_z(a.0, a.1)
+                .partial_cmp(&mesh.get_z(b.0, b.1))
+                .unwrap()
+        });
+        out
+    }
+
+    fn step(&self, result: &mut ErosionResult) {
+        let mesh = &result.get_mesh().clone();
+        let downhill_map = DownhillMap::with_neighbourhood(mesh, self.neighbourhood);
+        let steepest_downhill_map: Box<SingleDownhillMap> =
+            Box::new(SteepestDownhillMap::new(mesh, &downhill_map));
+        let flow_map = FlowMap::from_topological(mesh, &steepest_downhill_map);
+        let directions = self.neighbourhood.get_directions();
+
+        // Cells drain to strictly lower cells, so going from lowest to highest means each
+        // cell's receiver has already been updated
+        let mut out = mesh.clone();
+        let (width, height) = mesh.get_shape();
+        let mut eroded = na::DMatrix::zeros(width, height);
+        for (x, y) in StreamPowerErosion::get_cells_lowest_first(mesh) {
+            let direction = directions[steepest_downhill_map.get_direction(x, y)];
+            let slope = get_slope(mesh, x, y, direction);
+            let area = flow_map.get_flow(x, y);
+            let erosion = self.erodibility
+                * area.powf(self.area_exponent)
+                * slope.powf(self.slope_exponent)
+                * self.time_step;
+            let uplifted = mesh.get_z(x, y) + self.uplift * self.time_step;
+            let receiver_z = out.get_z(x + direction.0, y + direction.1);
+            let z = (uplifted - erosion).max(receiver_z + MIN_DROP);
+            out.set_z(x, y, z);
+            // Raising a cell to stay above its receiver is a correction, not deposition
+            let eroded_here = (uplifted - z).max(0.0);
+            result.record_change(x, y, -eroded_here);
+            eroded[(x as usize, y as usize)] = eroded_here;
+        }
+        let sediment = FlowMap::from_precipitation(mesh, &steepest_downhill_map, &eroded);
+        result.record_all_sediment(sediment.get_flow_matrix());
+        result.set_mesh(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use mesh_splitter::MeshSplitter;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn mesh() -> Mesh {
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let mut mesh = Mesh::new(1, 0.0);
+        mesh.set_z(0, 0, 1.0);
+        MeshSplitter::split_n_times(&mesh, &mut rng, (0.0, 1.0), 5)
+    }
+
+    #[test]
+    fn erosion_should_retain_downhill_property() {
+        for neighbourhood in [Neighbourhood::Four, Neighbourhood::Eight].iter() {
+            let erosion = StreamPowerErosion::new(0.5, 0.5, 1.0, 0.01, 1.0, *neighbourhood);
+
+            let result = erosion.erode(mesh(), 20);
+
+            let downhill = DownhillMap::with_neighbourhood(result.get_mesh(), *neighbourhood);
+            assert_eq!(downhill.all_cells_have_downhill(), true);
+        }
+    }
+
+    #[test]
+    fn uplift_without_erosion_should_raise_all_cells() {
+        let erosion = StreamPowerErosion::new(0.0, 0.5, 1.0, 0.1, 0.5, Neighbourhood::Four);
+        let before = mesh();
+
+        let result = erosion.erode(before.clone(), 4);
+        let after = result.get_mesh();
+
+        for x in 0..before.get_width() {
+            for y in 0..before.get_height() {
+                let expected = before.get_z(x, y) + 0.2;
+                assert!((after.get_z(x, y) - expected).abs() < 1e-9);
+            }
+        }
+        let (width, height) = before.get_shape();
+        assert_eq!(result.get_erosion(), &na::DMatrix::zeros(width, height));
+        assert_eq!(result.get_deposition(), &na::DMatrix::zeros(width, height));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.6, 0.5]));
+        let erosion = StreamPowerErosion::new(0.5, 1.0, 1.0, 0.1, 1.0, Neighbourhood::Four);
+        let mut actual = ErosionResult::new(mesh);
+
+        erosion.step(&mut actual);
+
+        let mut expected = Mesh::new(2, 0.0);
+        expected.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.3, 0.25, 0.4, 0.35]));
+        for x in 0..2 {
+            for y in 0..2 {
+                assert!((actual.get_mesh().get_z(x, y) - expected.get_z(x, y)).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn erosion_should_not_cut_below_receiver() {
+        let mut mesh = Mesh::new(2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(2, 2, &[0.4, 0.3, 0.6, 0.5]));
+        let erosion = StreamPowerErosion::new(100.0, 1.0, 1.0, 0.0, 1.0, Neighbourhood::Four);
+        let mut result = ErosionResult::new(mesh);
+
+        erosion.step(&mut result);
+
+        let actual = result.get_mesh();
+        assert_eq!(DownhillMap::new(actual).all_cells_have_downhill(), true);
+        assert_eq!(actual.get_z(0, 1), MIN_DROP);
+        assert!((result.get_erosion()[(0, 1)] - (0.3 - MIN_DROP)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn raising_cell_above_receiver_should_not_count_as_deposition() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_element(3, 3, 0.5));
+        mesh.set_z(1, 0, 0.2);
+        mesh.set_z(1, 1, 0.2 + 1e-12);
+        let erosion = StreamPowerErosion::new(0.0, 1.0, 1.0, 0.1, 1.0, Neighbourhood::Four);
+        let mut result = ErosionResult::new(mesh);
+
+        erosion.step(&mut result);
+
+        let actual = result.get_mesh();
+        assert_eq!(actual.get_z(1, 1), actual.get_z(1, 0) + MIN_DROP);
+        assert_eq!(result.get_erosion(), &na::DMatrix::zeros(3, 3));
+        assert_eq!(result.get_deposition(), &na::DMatrix::zeros(3, 3));
+    }
+
+}
@@ -0,0 +1,347 @@
+use mesh::Mesh;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+type Vector = na::Vector3<f64>;
+
+/// A triangulated surface through the cells of a mesh, for export to 3D tools. Cells are one
+/// unit apart with z up. y is flipped so that the first row of the mesh is furthest north
+/// (largest y) when viewed from above.
+#[derive(Debug, PartialEq)]
+pub struct Surface {
+    vertices: Vec<Vector>,
+    normals: Vec<Vector>,
+    // Each corner is an index into vertices and an index into normals
+    faces: Vec<[(usize, usize); 3]>,
+}
+
+impl Surface {
+    /// Samples every `stride` cells in each direction, always including the last row and
+    /// column so the surface covers the whole mesh. Heights are multiplied by `exaggeration`.
+    /// With a `base`, walls are dropped from the edges to a floor at that (unexaggerated)
+    /// height, making a closed solid suitable for 3D printing.
+    pub fn new(mesh: &Mesh, exaggeration: f64, stride: usize, base: Option<f64>) -> Surface {
+        assert!(stride > 0, "Stride must be positive");
+        assert!(
+            mesh.get_width() > 1 && mesh.get_height() > 1,
+            "Mesh must be at least two cells wide and high"
+        );
+        let xs = Surface::sample(mesh.get_width(), stride);
+        let ys = Surface::sample(mesh.get_height(), stride);
+        let (columns, rows) = (xs.len(), ys.len());
+        let top = mesh.get_height() as f64 - 1.0;
+
+        let mut vertices = Vec::with_capacity(columns * rows);
+        for y in ys.iter() {
+            for x in xs.iter() {
+                let z = mesh.get_z(*x, *y) * exaggeration;
+                vertices.push(Vector::new(*x as f64, top - *y as f64, z));
+            }
+        }
+        let index = |column: usize, row: usize| row * columns + column;
+
+        let mut triangles = Vec::with_capacity((columns - 1) * (rows - 1) * 2);
+        for row in 0..rows - 1 {
+            for column in 0..columns - 1 {
+                let a = index(column, row);
+                let b = index(column + 1, row);
+                let c = index(column, row + 1);
+                let d = index(column + 1, row + 1);
+                triangles.push([a, c, b]);
+                triangles.push([b, c, d]);
+            }
+        }
+
+        let mut normals = vec![Vector::zeros(); vertices.len()];
+        for triangle in triangles.iter() {
+            let normal = Surface::get_face_normal(&vertices, *triangle);
+            for vertex in triangle.iter() {
+                normals[*vertex] += normal;
+            }
+        }
+        for normal in normals.iter_mut() {
+            *normal = normal.normalize();
+        }
+
+        let mut out = Surface {
+            vertices,
+            normals,
+            faces: triangles
+                .iter()
+                .map(|[a, b, c]| [(*a, *a), (*b, *b), (*c, *c)])
+                .collect(),
+        };
+
+        if let Some(base) = base {
+            let mut perimeter: Vec<usize> = vec![];
+            perimeter.extend((0..columns).map(|column| index(column, 0)));
+            perimeter.extend((1..rows).map(|row| index(columns - 1, row)));
+            perimeter.extend((0..columns - 1).rev().map(|column| index(column, rows - 1)));
+            perimeter.extend((1..rows - 1).rev().map(|row| index(0, row)));
+            out.add_base(&perimeter, base * exaggeration);
+        }
+
+        out
+    }
+
+    fn sample(size: i32, stride: usize) -> Vec<i32> {
+        let mut out: Vec<i32> = (0..size).step_by(stride).collect();
+        if out[out.len() - 1] != size - 1 {
+            out.push(size - 1);
+        }
+        out
+    }
+
+    // Unit normal of a counter-clockwise triangle, or zero if it is degenerate
+    fn get_face_normal(vertices: &[Vector], triangle: [usize; 3]) -> Vector {
+        let [a, b, c] = triangle;
+        let normal = (vertices[b] - vertices[a]).cross(&(vertices[c] - vertices[a]));
+        let norm = normal.norm();
+        if norm == 0.0 {
+            normal
+        } else {
+            normal / norm
+        }
+    }
+
+    fn add_face(&mut self, triangle: [usize; 3]) {
+        let normal = Surface::get_face_normal(&self.vertices, triangle);
+        let n = self.normals.len();
+        self.normals.push(normal);
+        let [a, b, c] = triangle;
+        self.faces.push([(a, n), (b, n), (c, n)]);
+    }
+
+    // `perimeter` runs clockwise when viewed from above
+    fn add_base(&mut self, perimeter: &[usize], z: f64) {
+        let first = self.vertices.len();
+        for vertex in perimeter.iter() {
+            let position = self.vertices[*vertex];
+            self.vertices.push(Vector::new(position.x, position.y, z));
+        }
+        let centre = self.vertices.len();
+        let sum = perimeter
+            .iter()
+            .fold(Vector::zeros(), |sum, vertex| sum + self.vertices[*vertex]);
+        let n = perimeter.len() as f64;
+        self.vertices.push(Vector::new(sum.x / n, sum.y / n, z));
+
+        for i in 0..perimeter.len() {
+            let j = (i + 1) % perimeter.len();
+            let (top_i, top_j) = (perimeter[i], perimeter[j]);
+            let (bottom_i, bottom_j) = (first + i, first + j);
+            self.add_face([top_i, top_j, bottom_i]);
+            self.add_face([top_j, bottom_j, bottom_i]);
+            self.add_face([centre, bottom_i, bottom_j]);
+        }
+    }
+
+    pub fn get_vertices(&self) -> &Vec<Vector> {
+        &self.vertices
+    }
+
+    pub fn get_normals(&self) -> &Vec<Vector> {
+        &self.normals
+    }
+
+    pub fn get_triangle_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    /// Corner positions of every triangle, counter-clockwise when viewed from outside.
+    pub fn get_triangles(&self) -> Vec<[Vector; 3]> {
+        self.faces
+            .iter()
+            .map(|face| {
+                [
+                    self.vertices[face[0].0],
+                    self.vertices[face[1].0],
+                    self.vertices[face[2].0],
+                ]
+            })
+            .collect()
+    }
+
+    pub fn write_obj<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for v in self.vertices.iter() {
+            writeln!(writer, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+        for n in self.normals.iter() {
+            writeln!(writer, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        for face in self.faces.iter() {
+            // OBJ indices start at 1
+            writeln!(
+                writer,
+                "f {}//{} {}//{} {}//{}",
+                face[0].0 + 1,
+                face[0].1 + 1,
+                face[1].0 + 1,
+                face[1].1 + 1,
+                face[2].0 + 1,
+                face[2].1 + 1
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_stl<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        fn write_vector<W: Write>(writer: &mut W, vector: &Vector) -> io::Result<()> {
+            for value in vector.iter() {
+                writer.write_all(&(*value as f32).to_bits().to_le_bytes())?;
+            }
+            Ok(())
+        }
+
+        writer.write_all(&[0; 80])?;
+        writer.write_all(&(self.faces.len() as u32).to_le_bytes())?;
+        for face in self.faces.iter() {
+            let triangle = [face[0].0, face[1].0, face[2].0];
+            write_vector(
+                &mut writer,
+                &Surface::get_face_normal(&self.vertices, triangle),
+            )?;
+            for vertex in triangle.iter() {
+                write_vector(&mut writer, &self.vertices[*vertex])?;
+            }
+            writer.write_all(&[0; 2])?;
+        }
+        Ok(())
+    }
+
+    pub fn save_obj<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_obj(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn save_stl<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_stl(&mut writer)?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashMap;
+
+    fn mesh() -> Mesh {
+        let mut mesh = Mesh::with_size(5, 3, 0.0);
+        for x in 0..5 {
+            for y in 0..3 {
+                mesh.set_z(x, y, (x * y) as f64 * 0.1);
+            }
+        }
+        mesh
+    }
+
+    // Every edge must be shared by exactly two triangles which traverse it in opposite
+    // directions
+    fn is_closed(surface: &Surface) -> bool {
+        let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+        for face in surface.faces.iter() {
+            for i in 0..3 {
+                *edges.entry((face[i].0, face[(i + 1) % 3].0)).or_insert(0) += 1;
+            }
+        }
+        edges
+            .iter()
+            .all(|((a, b), count)| *count == 1 && edges.get(&(*b, *a)) == Some(&1))
+    }
+
+    #[test]
+    fn test_new() {
+        let surface = Surface::new(&mesh(), 2.0, 1, None);
+
+        assert_eq!(surface.get_vertices().len(), 15);
+        assert_eq!(surface.get_triangle_count(), 16);
+        assert_eq!(surface.get_vertices()[0], Vector::new(0.0, 2.0, 0.0));
+        assert_eq!(surface.get_vertices()[14], Vector::new(4.0, 0.0, 1.6));
+        assert_eq!(is_closed(&surface), false);
+    }
+
+    #[test]
+    fn triangles_should_face_up() {
+        let surface = Surface::new(&mesh(), 1.0, 1, None);
+
+        for triangle in surface.get_triangles() {
+            let normal = (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0]));
+            assert!(normal.z > 0.0);
+        }
+        for normal in surface.get_normals() {
+            assert!(normal.z > 0.0);
+            assert!((normal.norm() - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn flat_mesh_should_have_vertical_normals() {
+        let surface = Surface::new(&Mesh::new(3, 0.0), 1.0, 1, None);
+
+        for normal in surface.get_normals() {
+            assert_eq!(*normal, Vector::new(0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn stride_should_keep_last_row_and_column() {
+        let surface = Surface::new(&mesh(), 1.0, 3, None);
+
+        let xs: Vec<f64> = surface.get_vertices()[0..3].iter().map(|v| v.x).collect();
+        assert_eq!(xs, vec![0.0, 3.0, 4.0]);
+        assert_eq!(surface.get_vertices().len(), 6);
+        assert_eq!(surface.get_triangle_count(), 4);
+    }
+
+    #[test]
+    fn base_should_close_surface() {
+        let surface = Surface::new(&mesh(), 1.0, 1, Some(-1.0));
+
+        assert_eq!(is_closed(&surface), true);
+        let perimeter = 12;
+        assert_eq!(surface.get_vertices().len(), 15 + perimeter + 1);
+        assert_eq!(surface.get_triangle_count(), 16 + perimeter * 3);
+        assert_eq!(surface.get_vertices()[15 + perimeter].z, -1.0);
+    }
+
+    #[test]
+    fn base_should_close_decimated_surface() {
+        let surface = Surface::new(&mesh(), 3.0, 2, Some(0.0));
+
+        assert_eq!(is_closed(&surface), true);
+    }
+
+    #[test]
+    fn test_write_obj() {
+        let surface = Surface::new(&Mesh::new(2, 0.0), 1.0, 1, None);
+        let mut obj = vec![];
+
+        surface.write_obj(&mut obj).unwrap();
+
+        let obj = String::from_utf8(obj).unwrap();
+        let lines: Vec<&str> = obj.lines().collect();
+        assert_eq!(lines.len(), 4 + 4 + 2);
+        assert_eq!(lines[0], "v 0 1 0");
+        assert_eq!(lines[4], "vn 0 0 1");
+        assert_eq!(lines[8], "f 1//1 3//3 2//2");
+    }
+
+    #[test]
+    fn test_write_stl() {
+        let surface = Surface::new(&mesh(), 1.0, 1, Some(-1.0));
+        let mut stl = vec![];
+
+        surface.write_stl(&mut stl).unwrap();
+
+        let count = surface.get_triangle_count();
+        assert_eq!(stl.len(), 84 + count * 50);
+        assert_eq!(&stl[80..84], &(count as u32).to_le_bytes());
+        let normal_z = f32::from_bits(u32::from_le_bytes([stl[92], stl[93], stl[94], stl[95]]));
+        assert!(normal_z > 0.0);
+    }
+
+}
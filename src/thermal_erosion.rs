@@ -0,0 +1,151 @@
+use downhill_map::{get_distance, Neighbourhood};
+use erosion::ErosionResult;
+use mesh::Mesh;
+
+pub struct ThermalErosion {}
+
+impl ThermalErosion {
+    /// Wherever the slope from a cell to a neighbour is steeper than `talus`, material slides
+    /// from the cell to that neighbour. Material is never moved off the mesh, so the total
+    /// height of the mesh is unchanged.
+    pub fn erode(
+        mesh: Mesh,
+        talus: f64,
+        iterations: usize,
+        neighbourhood: Neighbourhood,
+    ) -> ErosionResult {
+        let mut result = ErosionResult::new(mesh);
+        for _ in 0..iterations {
+            ThermalErosion::iterate(&mut result, talus, neighbourhood);
+        }
+        result
+    }
+
+    fn iterate(result: &mut ErosionResult, talus: f64, neighbourhood: Neighbourhood) {
+        let directions = neighbourhood.get_directions();
+        let mesh = result.get_mesh().clone();
+        let mut out = mesh.clone();
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                let z = mesh.get_z(x, y);
+                let excesses: Vec<f64> = directions
+                    .iter()
+                    .map(|direction| match mesh.get_neighbour(x, y, *direction) {
+                        Some((nx, ny)) => z - mesh.get_z(nx, ny) - talus * get_distance(*direction),
+                        None => 0.0,
+                    })
+                    .collect();
+                let total: f64 = excesses.iter().filter(|excess| **excess > 0.0).sum();
+                if total <= 0.0 {
+                    continue;
+                }
+                let max = excesses.iter().cloned().fold(0.0, f64::max);
+                // Moving half the largest excess means a pair of cells never overshoot each other
+                let moved = max / 2.0;
+                for (direction, excess) in directions.iter().zip(excesses.iter()) {
+                    if *excess > 0.0 {
+                        let (nx, ny) = mesh.get_neighbour(x, y, *direction).unwrap();
+                        let share = moved * excess / total;
+                        let nz = out.get_z(nx, ny);
+                        out.set_z(nx, ny, nz + share);
+                        result.record_change(nx, ny, share);
+                    }
+                }
+                let before = out.get_z(x, y);
+                out.set_z(x, y, before - moved);
+                result.record_change(x, y, -moved);
+                result.record_sediment(x, y, moved);
+            }
+        }
+        result.set_mesh(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn get_max_slope(mesh: &Mesh, neighbourhood: Neighbourhood) -> f64 {
+        let mut out: f64 = 0.0;
+        for x in 0..mesh.get_width() {
+            for y in 0..mesh.get_height() {
+                for direction in neighbourhood.get_directions().iter() {
+                    if let Some((nx, ny)) = mesh.get_neighbour(x, y, *direction) {
+                        let drop = mesh.get_z(x, y) - mesh.get_z(nx, ny);
+                        out = out.max(drop / get_distance(*direction));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn spike() -> Mesh {
+        let mut mesh = Mesh::new(9, 0.0);
+        mesh.set_z(4, 4, 10.0);
+        mesh
+    }
+
+    #[test]
+    fn test_iterate() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z(1, 1, 3.0);
+
+        let mut actual = ErosionResult::new(mesh);
+
+        ThermalErosion::iterate(&mut actual, 1.0, Neighbourhood::Four);
+
+        let mut expected = Mesh::new(3, 0.0);
+        expected.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.0, 0.25, 0.0, 0.25, 2.0, 0.25, 0.0, 0.25, 0.0],
+        ));
+        assert_eq!(actual.get_mesh(), &expected);
+        assert_eq!(
+            actual.get_deposition(),
+            &na::DMatrix::from_row_slice(3, 3, &[0.0, 0.25, 0.0, 0.25, 0.0, 0.25, 0.0, 0.25, 0.0])
+        );
+        let mut expected_erosion = na::DMatrix::zeros(3, 3);
+        expected_erosion[(1, 1)] = 1.0;
+        assert_eq!(actual.get_erosion(), &expected_erosion);
+        assert_eq!(actual.get_sediment(), &expected_erosion);
+    }
+
+    #[test]
+    fn gentle_slopes_should_not_change() {
+        let mut mesh = Mesh::new(3, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            3,
+            &[0.1, 0.8, 0.2, 0.3, 0.5, 0.9, 0.6, 0.4, 0.7],
+        ));
+
+        assert_eq!(
+            ThermalErosion::erode(mesh.clone(), 1.0, 10, Neighbourhood::Eight).get_mesh(),
+            &mesh
+        );
+    }
+
+    #[test]
+    fn max_slope_should_be_bounded_by_talus() {
+        for neighbourhood in [Neighbourhood::Four, Neighbourhood::Eight].iter() {
+            let actual = ThermalErosion::erode(spike(), 1.0, 200, *neighbourhood);
+
+            assert!(get_max_slope(actual.get_mesh(), *neighbourhood) < 1.0 + 1e-3);
+        }
+    }
+
+    #[test]
+    fn erosion_should_conserve_material() {
+        let actual = ThermalErosion::erode(spike(), 0.5, 50, Neighbourhood::Eight);
+
+        let total: f64 = actual.get_mesh().get_z_vector().iter().sum();
+        assert!((total - 10.0).abs() < 1e-9);
+        let eroded: f64 = actual.get_erosion().iter().sum();
+        let deposited: f64 = actual.get_deposition().iter().sum();
+        assert!((eroded - deposited).abs() < 1e-9);
+    }
+
+}
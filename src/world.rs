@@ -0,0 +1,334 @@
+use bincode::{self, Options};
+use flow_map::FlowMap;
+use isometric::terrain::{Edge, Node};
+use mesh::Mesh;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"PNWD";
+const VERSION: u32 = 1;
+// Stops a corrupt length from making bincode allocate more than any real world needs
+const MAX_SIZE: u64 = 1 << 32;
+
+#[derive(Debug)]
+pub enum WorldError {
+    Io(io::Error),
+    NotAWorld,
+    UnsupportedVersion(u32),
+    Corrupt(bincode::Error),
+    Inconsistent(String),
+}
+
+impl fmt::Display for WorldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorldError::Io(error) => write!(f, "{}", error),
+            WorldError::NotAWorld => write!(f, "Not a world file"),
+            WorldError::UnsupportedVersion(version) => write!(
+                f,
+                "World file version {} is not supported (expected {})",
+                version, VERSION
+            ),
+            WorldError::Corrupt(error) => write!(f, "World file is corrupt: {}", error),
+            WorldError::Inconsistent(message) => {
+                write!(f, "World file is inconsistent: {}", message)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for WorldError {
+    fn from(error: io::Error) -> WorldError {
+        WorldError::Io(error)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Junction {
+    x: usize,
+    y: usize,
+    width: f32,
+    height: f32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct River {
+    from: (usize, usize),
+    to: (usize, usize),
+}
+
+/// A generated world that can be cached and reloaded instead of regenerated. Files start with
+/// the magic bytes `PNWD` and a little-endian version number, followed by the world encoded
+/// with bincode.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct World {
+    mesh: Mesh,
+    flow_map: FlowMap,
+    junctions: Vec<Junction>,
+    rivers: Vec<River>,
+}
+
+impl World {
+    pub fn new(mesh: Mesh, flow_map: FlowMap, junctions: &[Node], rivers: &[Edge]) -> World {
+        World {
+            mesh,
+            flow_map,
+            junctions: junctions
+                .iter()
+                .map(|junction| Junction {
+                    x: junction.position().x,
+                    y: junction.position().y,
+                    width: junction.width(),
+                    height: junction.height(),
+                })
+                .collect(),
+            rivers: rivers
+                .iter()
+                .map(|river| River {
+                    from: (river.from().x, river.from().y),
+                    to: (river.to().x, river.to().y),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get_mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    pub fn get_flow_map(&self) -> &FlowMap {
+        &self.flow_map
+    }
+
+    pub fn get_junctions(&self) -> Vec<Node> {
+        self.junctions
+            .iter()
+            .map(|junction| {
+                Node::new(
+                    na::Vector2::new(junction.x, junction.y),
+                    junction.width,
+                    junction.height,
+                )
+            })
+            .collect()
+    }
+
+    pub fn get_rivers(&self) -> Vec<Edge> {
+        self.rivers
+            .iter()
+            .map(|river| {
+                Edge::new(
+                    na::Vector2::new(river.from.0, river.from.1),
+                    na::Vector2::new(river.to.0, river.to.1),
+                )
+            })
+            .collect()
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), WorldError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        World::get_options()
+            .serialize_into(writer, self)
+            .map_err(WorldError::Corrupt)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<World, WorldError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(WorldError::NotAWorld);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(WorldError::UnsupportedVersion(version));
+        }
+        let world: World = World::get_options()
+            .deserialize_from(reader)
+            .map_err(WorldError::Corrupt)?;
+        world.check_shapes()?;
+        Ok(world)
+    }
+
+    // The same encoding as `bincode::serialize`, with a limit on the size
+    fn get_options() -> impl Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_SIZE)
+    }
+
+    fn check_shapes(&self) -> Result<(), WorldError> {
+        let shape = self.mesh.get_shape();
+        let z_shape = self.mesh.get_z_vector().shape();
+        if z_shape != shape {
+            return Err(WorldError::Inconsistent(format!(
+                "Mesh is {:?} but has {:?} heights",
+                shape, z_shape
+            )));
+        }
+        let flow_shape = self.flow_map.get_flow_matrix().shape();
+        if flow_shape != shape {
+            return Err(WorldError::Inconsistent(format!(
+                "Mesh is {:?} but flow map is {:?}",
+                shape, flow_shape
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WorldError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<World, WorldError> {
+        World::read(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use downhill_map::DownhillMap;
+    use edge_policy::{Boundary, EdgePolicy};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use single_downhill_map::{RandomDownhillMap, SingleDownhillMap};
+
+    fn mesh() -> Mesh {
+        let mut mesh = Mesh::with_size(3, 2, 0.0);
+        mesh.set_z_vector(na::DMatrix::from_row_slice(
+            3,
+            2,
+            &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        ));
+        mesh.set_edge_policy(EdgePolicy::new(
+            Boundary::Wrap,
+            Boundary::Constant(0.0),
+            Boundary::Wrap,
+            Boundary::Clamp,
+        ));
+        mesh
+    }
+
+    fn world() -> World {
+        let mesh = mesh();
+        let downhill_map = DownhillMap::new(&mesh);
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let downhill_map: Box<SingleDownhillMap> =
+            Box::new(RandomDownhillMap::new(&downhill_map, &mut rng));
+        let flow_map = FlowMap::from(&mesh, &downhill_map);
+        let from = na::Vector2::new(2, 1);
+        let to = na::Vector2::new(2, 0);
+        World::new(
+            mesh,
+            flow_map,
+            &[Node::new(from, 0.5, 0.0), Node::new(to, 0.75, 0.0)],
+            &[Edge::new(from, to)],
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let world = world();
+        let mut bytes = vec![];
+
+        world.write(&mut bytes).unwrap();
+        let actual = World::read(&bytes[..]).unwrap();
+
+        assert_eq!(&bytes[0..8], &[b'P', b'N', b'W', b'D', 1, 0, 0, 0]);
+        assert_eq!(actual, world);
+        assert_eq!(actual.get_junctions(), world.get_junctions());
+        assert_eq!(
+            actual.get_rivers(),
+            vec![Edge::new(na::Vector2::new(2, 1), na::Vector2::new(2, 0))]
+        );
+    }
+
+    #[test]
+    fn downhill_maps_should_round_trip() {
+        let mesh = mesh();
+        let downhill_map = DownhillMap::new(&mesh);
+        let mut rng = Box::new(StdRng::seed_from_u64(0));
+        let random_downhill_map = RandomDownhillMap::new(&downhill_map, &mut rng);
+
+        let bytes = bincode::serialize(&downhill_map).unwrap();
+        assert_eq!(
+            bincode::deserialize::<DownhillMap>(&bytes).unwrap(),
+            downhill_map
+        );
+        let bytes = bincode::serialize(&random_downhill_map).unwrap();
+        assert_eq!(
+            bincode::deserialize::<RandomDownhillMap>(&bytes).unwrap(),
+            random_downhill_map
+        );
+    }
+
+    #[test]
+    fn read_should_reject_other_files() {
+        match World::read(&b"\x89PNG\r\n\x1a\n"[..]) {
+            Err(WorldError::NotAWorld) => (),
+            other => panic!("Expected NotAWorld, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_should_reject_other_versions() {
+        let mut bytes = vec![];
+        world().write(&mut bytes).unwrap();
+        bytes[4] = 2;
+
+        match World::read(&bytes[..]) {
+            Err(WorldError::UnsupportedVersion(2)) => (),
+            other => panic!("Expected UnsupportedVersion(2), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_should_reject_truncated_files() {
+        let mut bytes = vec![];
+        world().write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+
+        match World::read(&bytes[..]) {
+            Err(WorldError::Corrupt(_)) => (),
+            other => panic!("Expected Corrupt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_should_reject_corrupt_lengths() {
+        let mut bytes = vec![];
+        world().write(&mut bytes).unwrap();
+        // The number of heights follows the mesh width and height
+        assert_eq!(&bytes[16..24], &6u64.to_le_bytes());
+        bytes[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        match World::read(&bytes[..]) {
+            Err(WorldError::Corrupt(_)) => (),
+            other => panic!("Expected Corrupt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_should_reject_inconsistent_shapes() {
+        let mut bytes = vec![];
+        world().write(&mut bytes).unwrap();
+        assert_eq!(&bytes[8..12], &3i32.to_le_bytes());
+        bytes[8..12].copy_from_slice(&4i32.to_le_bytes());
+
+        match World::read(&bytes[..]) {
+            Err(WorldError::Inconsistent(_)) => (),
+            other => panic!("Expected Inconsistent, got {:?}", other),
+        }
+    }
+
+}